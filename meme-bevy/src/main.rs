@@ -1,21 +1,27 @@
+use std::time::Duration;
+
 use arboard::Clipboard;
 use bevy::{
+    core::{Time, Timer},
     ecs::prelude::*,
-    input::Input,
+    input::{keyboard::KeyCode, Input},
     math::*,
     prelude::{App, Assets, Children, GlobalTransform, Handle, MouseButton, Transform},
     render2::{
         camera::OrthographicCameraBundle,
+        color::Color,
         render_resource::{Extent3d, TextureDimension, TextureFormat},
         texture::Image,
     },
     sprite2::{PipelinedSpriteBundle, Sprite},
-    window::Windows,
+    window::{FileDragAndDrop, WindowDescriptor, WindowId, WindowResized, Windows},
     PipelinedDefaultPlugins,
 };
-use memeinator::Config;
+use memeinator::{Config, FillMode, MemeConfig, MemeField, RenderContext};
+use settings::Settings;
 use ui4::plugin::{Ui4Plugin, Ui4Root};
 
+mod settings;
 mod ui;
 
 #[derive(ui4::prelude::Lens, Default)]
@@ -25,10 +31,57 @@ pub(crate) struct TextRects {
 
 #[derive(ui4::prelude::Lens, Default)]
 pub(crate) struct MemeName(String);
-#[derive(PartialEq, Clone, Copy)]
-enum MemeTextColor {
-    Black,
-    White,
+
+#[derive(ui4::prelude::Lens, Default)]
+pub(crate) struct GifPath(String);
+
+/// The local source to save templates to, the first one if empty
+#[derive(ui4::prelude::Lens, Default)]
+pub(crate) struct SaveSource(String);
+
+/// The text color as a hex code, `#rgb`, `#rgba`, `#rrggbb` or `#rrggbbaa`
+#[derive(ui4::prelude::Lens)]
+pub(crate) struct MemeTextColor(String);
+
+impl Default for MemeTextColor {
+    fn default() -> Self {
+        Self("#000000".to_owned())
+    }
+}
+
+impl MemeTextColor {
+    /// The color as RGBA from 0 to 1, if the hex code is valid
+    fn rgba(&self) -> Option<[f32; 4]> {
+        let hex = self.0.trim().trim_start_matches('#');
+        if !hex.is_ascii() {
+            return None;
+        }
+        let digits: Vec<u8> = match hex.len() {
+            3 | 4 => hex
+                .chars()
+                .map(|c| c.to_digit(16).map(|x| x as u8 * 17))
+                .collect::<Option<_>>()?,
+            6 | 8 => (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                .collect::<Option<_>>()?,
+            _ => return None,
+        };
+        let mut rgba = [1.; 4];
+        for (channel, x) in rgba.iter_mut().zip(digits) {
+            *channel = x as f32 / u8::MAX as f32;
+        }
+        Some(rgba)
+    }
+
+    fn from_rgba(rgba: [u8; 4]) -> Self {
+        let [r, g, b, a] = rgba;
+        if a == u8::MAX {
+            Self(format!("#{:02x}{:02x}{:02x}", r, g, b))
+        } else {
+            Self(format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a))
+        }
+    }
 }
 
 #[derive(Default, Component)]
@@ -37,21 +90,134 @@ struct TextRect {
     max: IVec2,
 }
 
+impl TextRect {
+    fn coords(&self) -> [i32; 4] {
+        [self.min.x, self.min.y, self.max.x, self.max.y]
+    }
+
+    fn coords_mut(&mut self) -> [&mut i32; 4] {
+        [
+            &mut self.min.x,
+            &mut self.min.y,
+            &mut self.max.x,
+            &mut self.max.y,
+        ]
+    }
+}
+
+/// The text in a box's coordinate inputs, kept in step with its `TextRect`
+#[derive(ui4::prelude::Lens, Default, Component)]
+pub(crate) struct RectInputs {
+    min_x: String,
+    min_y: String,
+    max_x: String,
+    max_y: String,
+}
+
+impl RectInputs {
+    /// The typed coordinates, `None` for inputs that aren't a number (yet)
+    fn parsed(&self) -> [Option<i32>; 4] {
+        [&self.min_x, &self.min_y, &self.max_x, &self.max_y].map(|x| x.trim().parse().ok())
+    }
+
+    fn fields_mut(&mut self) -> [&mut String; 4] {
+        [
+            &mut self.min_x,
+            &mut self.min_y,
+            &mut self.max_x,
+            &mut self.max_y,
+        ]
+    }
+}
+
+/// The stored field a `TextRect` was loaded from, keeping the settings the editor doesn't show
+#[derive(Component)]
+struct StoredField(MemeField);
+
+/// The template opened for editing, so saving it under the same name replaces it
+#[derive(Default)]
+struct LoadedTemplate(Option<(String, MemeConfig)>);
+
+/// The text previewed in a `TextRect`
+#[derive(ui4::prelude::Lens, Default, Component)]
+pub(crate) struct Caption(String);
+
+/// The rendered meme shown over the editor by "Toggle Preview"
+#[derive(Component)]
+struct PreviewSprite;
+
+/// The tint of a text box without a caption
+const BOX_COLOR: Color = Color::rgba(0.8, 0.8, 0.8, 0.4);
+
 struct OnePxHandle(Handle<Image>);
 
+/// A box as the undo history remembers it
+#[derive(Clone)]
+struct BoxState {
+    entity: Entity,
+    min: IVec2,
+    max: IVec2,
+    caption: String,
+    stored: Option<MemeField>,
+}
+
+/// Earlier and undone states of the boxes, for Ctrl+Z and Ctrl+Y
+#[derive(Default)]
+struct History {
+    undo: Vec<Vec<BoxState>>,
+    redo: Vec<Vec<BoxState>>,
+    current: Vec<BoxState>,
+}
+
+impl History {
+    /// Points every remembered state of a respawned box at its new entity
+    fn remap(&mut self, old: Entity, new: Entity) {
+        for state in self.undo.iter_mut().chain(&mut self.redo) {
+            for item in state.iter_mut().filter(|item| item.entity == old) {
+                item.entity = new;
+            }
+        }
+    }
+}
+
+/// The `TextRect` whose handle was grabbed last, moved by the arrow keys
+#[derive(Default)]
+struct SelectedRect(Option<Entity>);
+
+/// Whether a coordinate input was typed into since the last click, so the arrow keys are
+/// moving its cursor rather than the box
+#[derive(Default)]
+struct TypingCoords(bool);
+
 fn main() {
     let memecfg = Config::load().unwrap();
     let clipboard = Clipboard::new().unwrap();
+    let render_ctx = memecfg.render_context().unwrap_or_default();
+    let settings = Settings::load();
+    let mut window = WindowDescriptor::default();
+    if let Some((width, height)) = settings.window {
+        window.width = width;
+        window.height = height;
+    }
 
     App::new()
+        .insert_resource(window)
         .add_plugins(PipelinedDefaultPlugins)
         // .add_plugin(bevy_inspector_egui::WorldInspectorPlugin::default())
         .add_plugin(Ui4Plugin)
         .init_resource::<TextRects>()
         .init_resource::<MemeName>()
-        .insert_resource(MemeTextColor::Black)
+        .init_resource::<GifPath>()
+        .insert_resource(SaveSource(settings.source.clone().unwrap_or_default()))
+        .insert_resource(settings)
+        .init_resource::<LoadedTemplate>()
+        .init_resource::<SelectedRect>()
+        .init_resource::<TypingCoords>()
+        .init_resource::<History>()
+        .init_resource::<MemeTextColor>()
         .add_plugin(Ui4Root(ui::root))
         .insert_resource(memecfg)
+        .insert_resource(render_ctx)
         .insert_resource(clipboard)
         .add_startup_system(
             |mut commands: Commands, mut images: ResMut<Assets<Image>>| {
@@ -90,14 +256,130 @@ fn main() {
             },
         )
         .add_system(keep_on_edge_system.label("koe"))
+        .add_system(preview_size_system.after("koe"))
         .add_system(handle_system.after("koe").label("hs"))
+        .add_system(nudge_system.after("koe").before("hs"))
         .add_system(text_rect_system.after("hs"))
+        .add_system(rect_inputs_system.after("hs"))
+        .add_system(history_system.after("hs"))
+        .add_system(animate_meme_system)
+        .add_system(window_size_system)
+        .add_system(file_drop_system)
+        .add_system(caption_preview_system)
         .run();
 }
 
 #[derive(Component)]
 struct MemeSprite(f32); // aspect ratio (w / h)
 
+/// The frames of an animated template, shown on the `MemeSprite` in turn
+#[derive(Component)]
+struct MemeFrames {
+    frames: Vec<(Handle<Image>, Duration)>,
+    current: usize,
+    timer: Timer,
+}
+
+impl MemeFrames {
+    fn new(frames: Vec<(Handle<Image>, Duration)>) -> Self {
+        let timer = Timer::new(frames[0].1, false);
+        Self {
+            frames,
+            current: 0,
+            timer,
+        }
+    }
+}
+
+fn animate_meme_system(
+    time: Res<Time>,
+    mut q: Query<(&mut Handle<Image>, &mut MemeFrames), With<MemeSprite>>,
+) {
+    for (mut handle, mut frames) in q.iter_mut() {
+        if frames.timer.tick(time.delta()).just_finished() {
+            frames.current = (frames.current + 1) % frames.frames.len();
+            let (next, delay) = frames.frames[frames.current].clone();
+            *handle = next;
+            frames.timer = Timer::new(delay, false);
+        }
+    }
+}
+
+/// Loads an image dropped onto the window as the template image. GIFs are loaded animated.
+fn file_drop_system(
+    mut commands: Commands,
+    mut dropped: EventReader<FileDragAndDrop>,
+    mut images: ResMut<Assets<Image>>,
+    mut q: Query<(Entity, &mut Handle<Image>, &mut MemeSprite)>,
+) {
+    for event in dropped.iter() {
+        let path = match event {
+            FileDragAndDrop::DroppedFile { path_buf, .. } => path_buf,
+            _ => continue,
+        };
+        let is_gif = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+        let frames = if is_gif {
+            ui::load_gif_frames(path)
+        } else {
+            image::open(path).map(|image| vec![(image.to_rgba8(), Duration::ZERO)])
+        };
+        let frames = match frames {
+            Ok(frames) if !frames.is_empty() => frames,
+            Ok(_) => {
+                println!("{} has no frames", path.display());
+                continue;
+            }
+            Err(e) => {
+                println!("Can't load {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let (entity, mut handle, mut koe) = q.single_mut();
+        let (width, height) = frames[0].0.dimensions();
+        koe.0 = width as f32 / height as f32;
+        let animated = frames.len() > 1;
+        let frames: Vec<_> = frames
+            .into_iter()
+            .map(|(frame, delay)| {
+                let (width, height) = frame.dimensions();
+                let image = images.add(Image::new(
+                    Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    TextureDimension::D2,
+                    frame.into_raw(),
+                    TextureFormat::Rgba8UnormSrgb,
+                ));
+                (image, delay)
+            })
+            .collect();
+        *handle = frames[0].0.clone();
+        if animated {
+            commands.entity(entity).insert(MemeFrames::new(frames));
+        } else {
+            commands.entity(entity).remove::<MemeFrames>();
+        }
+    }
+}
+
+/// Remembers the window size for the next session
+fn window_size_system(mut settings: ResMut<Settings>, mut resized: EventReader<WindowResized>) {
+    let size = resized
+        .iter()
+        .filter(|event| event.id == WindowId::primary())
+        .last()
+        .map(|event| (event.width, event.height));
+    if size.is_some() && size != settings.window {
+        settings.window = size;
+        settings.save();
+    }
+}
+
 fn keep_on_edge_system(
     mut q: Query<(&mut Sprite, &mut Transform, &MemeSprite)>,
     windows: Res<Windows>,
@@ -116,6 +398,149 @@ fn keep_on_edge_system(
     }
 }
 
+fn preview_size_system(
+    mut preview_q: Query<&mut Sprite, (With<PreviewSprite>, Without<MemeSprite>)>,
+    meme_sprite: Query<&Sprite, With<MemeSprite>>,
+) {
+    let size = meme_sprite.single().custom_size;
+    for mut sprite in preview_q.iter_mut() {
+        sprite.custom_size = size;
+    }
+}
+
+/// Draws each box's caption into the box's sprite when the caption, the box or the text
+/// color changes
+fn caption_preview_system(
+    mut q: Query<(
+        &TextRect,
+        ChangeTrackers<TextRect>,
+        &Caption,
+        ChangeTrackers<Caption>,
+        &mut Handle<Image>,
+        &mut Sprite,
+    )>,
+    mut render_ctx: ResMut<RenderContext>,
+    mut images: ResMut<Assets<Image>>,
+    one_px: Res<OnePxHandle>,
+    text_color: Res<MemeTextColor>,
+) {
+    for (rect, rect_tracker, caption, caption_tracker, mut handle, mut sprite) in q.iter_mut() {
+        if !(rect_tracker.is_changed() || caption_tracker.is_changed() || text_color.is_changed()) {
+            continue;
+        }
+        let field = MemeField {
+            min: (
+                rect.min.x.min(rect.max.x).max(0) as u32,
+                rect.min.y.min(rect.max.y).max(0) as u32,
+            ),
+            max: (
+                rect.min.x.max(rect.max.x).max(0) as u32,
+                rect.min.y.max(rect.max.y).max(0) as u32,
+            ),
+            ..Default::default()
+        };
+        let (width, height) = (field.max.0 - field.min.0, field.max.1 - field.min.1);
+        if caption.0.is_empty() || width == 0 || height == 0 {
+            *handle = one_px.0.clone();
+            sprite.color = BOX_COLOR;
+            continue;
+        }
+
+        // keep the last preview while the color is being typed in
+        let text = match text_color.rgba() {
+            Some(text) => text,
+            None => continue,
+        };
+        let mask = render_ctx.text_mask(&field, FillMode::Fit, &caption.0, 600.);
+        let [box_r, box_g, box_b, box_a] = BOX_COLOR.as_rgba_f32();
+        let data = mask
+            .pixels()
+            .flat_map(|coverage| {
+                // the text over the translucent box
+                let coverage = coverage.0[0] as f32 / u8::MAX as f32 * text[3];
+                let alpha = coverage + box_a * (1. - coverage);
+                let blend = |text: f32, under: f32| {
+                    (text * coverage + under * box_a * (1. - coverage)) / alpha
+                };
+                [
+                    blend(text[0], box_r),
+                    blend(text[1], box_g),
+                    blend(text[2], box_b),
+                    alpha,
+                ]
+                .map(|x| (x * u8::MAX as f32).round() as u8)
+            })
+            .collect();
+        *handle = images.add(Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+        ));
+        sprite.color = Color::WHITE;
+    }
+}
+
+/// Moves boxes to the coordinates typed into their inputs, kept inside the image, and shows
+/// the coordinates of boxes moved some other way
+fn rect_inputs_system(
+    mut q: Query<(
+        &mut TextRect,
+        ChangeTrackers<TextRect>,
+        &mut RectInputs,
+        ChangeTrackers<RectInputs>,
+    )>,
+    mut typing: ResMut<TypingCoords>,
+    input: Res<Input<MouseButton>>,
+    images: Res<Assets<Image>>,
+    meme_sprite: Query<&Handle<Image>, With<MemeSprite>>,
+) {
+    if input.just_pressed(MouseButton::Left) {
+        typing.0 = false;
+    }
+    let meme_image = images.get(meme_sprite.single()).unwrap();
+    let size = meme_image.texture_descriptor.size;
+    let (width, height) = (size.width as i32, size.height as i32);
+    for (mut rect, rect_tracker, mut inputs, inputs_tracker) in q.iter_mut() {
+        if inputs_tracker.is_changed() && !inputs_tracker.is_added() {
+            typing.0 = true;
+        }
+        let (coords, typed) = (rect.coords(), inputs.parsed());
+        // inputs that aren't a number are being typed in, and don't move the box
+        let moved = coords
+            .iter()
+            .zip(&typed)
+            .any(|(coord, typed)| typed.is_some_and(|typed| typed != *coord));
+        if inputs_tracker.is_changed() && moved {
+            for (coord, typed) in rect.coords_mut().into_iter().zip(typed) {
+                if let Some(typed) = typed {
+                    *coord = typed;
+                }
+            }
+            let TextRect { min, max } = &mut *rect;
+            clamp_edges(HandleSide::Indifferent, &mut max.x, &mut min.x, width);
+            clamp_edges(HandleSide::Indifferent, &mut min.y, &mut max.y, height);
+            // show where a coordinate past the edge ended up
+            let clamped = rect.coords();
+            for ((field, coord), typed) in inputs.fields_mut().into_iter().zip(clamped).zip(typed) {
+                if typed.is_some_and(|typed| typed != coord) {
+                    *field = coord.to_string();
+                }
+            }
+        } else if rect_tracker.is_changed() && typed != coords.map(Some) {
+            for ((field, coord), typed) in inputs.fields_mut().into_iter().zip(coords).zip(typed) {
+                if typed != Some(coord) {
+                    *field = coord.to_string();
+                }
+            }
+        }
+    }
+}
+
 fn text_rect_system(
     mut rects_q: Query<
         (&mut Sprite, &mut Transform, &TextRect, &Children),
@@ -126,6 +551,7 @@ fn text_rect_system(
     images: Res<Assets<Image>>,
     windows: Res<Windows>,
     input: Res<Input<MouseButton>>,
+    mut selected: ResMut<SelectedRect>,
     mut commands: Commands,
 ) {
     let mpos = windows.get_primary().and_then(|w| {
@@ -164,6 +590,7 @@ fn text_rect_system(
                         commands.entity(entity).insert(ActiveHandle {
                             offset: mpos - gpos,
                         });
+                        selected.0 = Some(handle.rect);
                     }
                 }
             }
@@ -171,11 +598,17 @@ fn text_rect_system(
     }
 }
 
+/// How close, in screen pixels, a dragged edge has to come to the image edge or another box's
+/// edge to snap to it
+const SNAP_DISTANCE: f32 = 8.;
+
+#[allow(clippy::too_many_arguments)]
 fn handle_system(
     mut handle_q: Query<(Entity, &GlobalTransform, &RectHandle, &ActiveHandle)>,
-    mut text_item_q: Query<&mut TextRect>,
+    mut text_item_q: Query<(Entity, &mut TextRect)>,
     windows: Res<Windows>,
     input: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
     mut commands: Commands,
 
     images: Res<Assets<Image>>,
@@ -196,8 +629,18 @@ fn handle_system(
             let image_size = UVec2::new(image_size.width, image_size.height).as_vec2();
             let scale = image_size.x / meme_sprite_size.x; // aspect ratio preserved, so ratio same for both axes
 
+            // the other boxes' edges and the image's, to snap to
+            let mut guides_x = vec![0, image_size.x as i32];
+            let mut guides_y = vec![0, image_size.y as i32];
+            for (entity, rect) in text_item_q.iter() {
+                if entity != handle.rect {
+                    guides_x.extend([rect.min.x, rect.max.x]);
+                    guides_y.extend([rect.min.y, rect.max.y]);
+                }
+            }
+
             let delta = mpos - active.offset - transform.translation.truncate();
-            let mut text_item = text_item_q.get_mut(handle.rect).unwrap();
+            let (_, mut text_item) = text_item_q.get_mut(handle.rect).unwrap();
             let delta = delta * scale;
             match handle.x {
                 HandleSide::Indifferent => {}
@@ -217,12 +660,211 @@ fn handle_system(
                     text_item.max.y -= delta.y as i32;
                 }
             }
+
+            let text_item = &mut *text_item;
+            let (min, max) = (&mut text_item.min, &mut text_item.max);
+            // holding alt places edges freely
+            if !(keys.pressed(KeyCode::LAlt) || keys.pressed(KeyCode::RAlt)) {
+                let threshold = (SNAP_DISTANCE * scale).round() as i32;
+                snap_edges(handle.x, &mut max.x, &mut min.x, &guides_x, threshold);
+                snap_edges(handle.y, &mut min.y, &mut max.y, &guides_y, threshold);
+            }
+            clamp_edges(handle.x, &mut max.x, &mut min.x, image_size.x as i32);
+            clamp_edges(handle.y, &mut min.y, &mut max.y, image_size.y as i32);
         } else if input.just_released(MouseButton::Left) {
             commands.entity(entity).remove::<ActiveHandle>();
         }
     }
 }
 
+/// Moves the selected box a pixel per arrow key press, or 10 with shift held
+fn nudge_system(
+    keys: Res<Input<KeyCode>>,
+    selected: Res<SelectedRect>,
+    typing: Res<TypingCoords>,
+    mut text_item_q: Query<&mut TextRect>,
+    images: Res<Assets<Image>>,
+    meme_sprite: Query<&Handle<Image>, With<MemeSprite>>,
+) {
+    if typing.0 {
+        return;
+    }
+    let mut text_item = match selected.0.and_then(|rect| text_item_q.get_mut(rect).ok()) {
+        Some(text_item) => text_item,
+        None => return,
+    };
+    let step = if keys.pressed(KeyCode::LShift) || keys.pressed(KeyCode::RShift) {
+        10
+    } else {
+        1
+    };
+    let mut delta = IVec2::ZERO;
+    for (key, dir) in [
+        (KeyCode::Left, -IVec2::X),
+        (KeyCode::Right, IVec2::X),
+        (KeyCode::Up, -IVec2::Y),
+        (KeyCode::Down, IVec2::Y),
+    ] {
+        if keys.just_pressed(key) {
+            delta += dir * step;
+        }
+    }
+    if delta != IVec2::ZERO {
+        let meme_image = images.get(meme_sprite.single()).unwrap();
+        let size = meme_image.texture_descriptor.size;
+        let TextRect { min, max } = &mut *text_item;
+        *min += delta;
+        *max += delta;
+        clamp_edges(HandleSide::Both, &mut max.x, &mut min.x, size.width as i32);
+        clamp_edges(HandleSide::Both, &mut min.y, &mut max.y, size.height as i32);
+    }
+}
+
+/// Records every change to the boxes once it's finished, and steps back and forth through
+/// them on Ctrl+Z and Ctrl+Y (or Ctrl+Shift+Z)
+#[allow(clippy::too_many_arguments)]
+fn history_system(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    one_px: Res<OnePxHandle>,
+    mut history: ResMut<History>,
+    mut rects: ResMut<TextRects>,
+    mut text_item_q: Query<(&mut TextRect, &Caption, Option<&StoredField>)>,
+    active_q: Query<(), With<ActiveHandle>>,
+    meme_sprite: Query<Entity, With<MemeSprite>>,
+) {
+    let mut state = Vec::with_capacity(rects.rects.len());
+    for &entity in rects.rects.iter() {
+        match text_item_q.get(entity) {
+            Ok((rect, caption, stored)) => state.push(BoxState {
+                entity,
+                min: rect.min,
+                max: rect.max,
+                caption: caption.0.clone(),
+                stored: stored.map(|stored| stored.0.clone()),
+            }),
+            // spawned this frame, look again once it exists
+            Err(_) => return,
+        }
+    }
+    // a drag is one step, recorded when the handle is let go
+    if !active_q.is_empty() {
+        return;
+    }
+    let moved = state.len() != history.current.len()
+        || state
+            .iter()
+            .zip(&history.current)
+            .any(|(a, b)| (a.entity, a.min, a.max) != (b.entity, b.min, b.max));
+    // caption edits aren't steps of their own, but are kept for boxes that get respawned
+    let previous = std::mem::replace(&mut history.current, state);
+    if moved {
+        history.undo.push(previous);
+        history.redo.clear();
+    }
+
+    let ctrl = keys.pressed(KeyCode::LControl) || keys.pressed(KeyCode::RControl);
+    let shift = keys.pressed(KeyCode::LShift) || keys.pressed(KeyCode::RShift);
+    let target = if !ctrl {
+        None
+    } else if keys.just_pressed(KeyCode::Z) && !shift {
+        history.undo.pop().map(|target| (target, true))
+    } else if keys.just_pressed(KeyCode::Y) || (keys.just_pressed(KeyCode::Z) && shift) {
+        history.redo.pop().map(|target| (target, false))
+    } else {
+        None
+    };
+    let (mut target, undone) = match target {
+        Some(target) => target,
+        None => return,
+    };
+
+    let current = std::mem::take(&mut history.current);
+    for item in &current {
+        if !target.iter().any(|target| target.entity == item.entity) {
+            commands.entity(item.entity).despawn_recursive();
+        }
+    }
+    let root = meme_sprite.single();
+    for item in &mut target {
+        if let Ok((mut rect, ..)) = text_item_q.get_mut(item.entity) {
+            rect.min = item.min;
+            rect.max = item.max;
+        } else {
+            // removed since, bring it back
+            let e = ui::spawn_text_rect(&mut commands, &one_px.0, root, item.min, item.max);
+            commands.entity(e).insert(Caption(item.caption.clone()));
+            if let Some(stored) = &item.stored {
+                commands.entity(e).insert(StoredField(stored.clone()));
+            }
+            history.remap(item.entity, e);
+            item.entity = e;
+        }
+    }
+
+    rects.rects.clear();
+    for item in &target {
+        rects.rects.push(item.entity);
+    }
+    if undone {
+        history.redo.push(current);
+    } else {
+        history.undo.push(current);
+    }
+    history.current = target;
+}
+
+/// Moves the edges a handle drags onto the nearest guide within `threshold`. `positive` and
+/// `negative` are the edges the handle's `HandleSide::Positive` and `Negative` sides move.
+fn snap_edges(
+    side: HandleSide,
+    positive: &mut i32,
+    negative: &mut i32,
+    guides: &[i32],
+    threshold: i32,
+) {
+    let snap = |edge: i32| {
+        guides
+            .iter()
+            .map(|&guide| guide - edge)
+            .filter(|offset| offset.abs() <= threshold)
+            .min_by_key(|offset| offset.abs())
+    };
+    match side {
+        HandleSide::Indifferent => {}
+        HandleSide::Positive => *positive += snap(*positive).unwrap_or(0),
+        HandleSide::Negative => *negative += snap(*negative).unwrap_or(0),
+        HandleSide::Both => {
+            // the whole box moves, so whichever edge is closer to a guide wins
+            let offset = [snap(*positive), snap(*negative)]
+                .into_iter()
+                .flatten()
+                .min_by_key(|offset| offset.abs());
+            *positive += offset.unwrap_or(0);
+            *negative += offset.unwrap_or(0);
+        }
+    }
+}
+
+/// Keeps the edges a handle drags inside the image, from 0 to `limit`. A box moved whole is
+/// stopped at the image edge rather than squashed against it.
+fn clamp_edges(side: HandleSide, positive: &mut i32, negative: &mut i32, limit: i32) {
+    if let HandleSide::Both = side {
+        let (low, high) = (*positive.min(negative), *positive.max(negative));
+        let shift = if low < 0 {
+            -low
+        } else if high > limit {
+            limit - high
+        } else {
+            0
+        };
+        *positive += shift;
+        *negative += shift;
+    }
+    *positive = (*positive).clamp(0, limit);
+    *negative = (*negative).clamp(0, limit);
+}
+
 #[derive(Copy, Clone)]
 enum HandleSide {
     Indifferent,
@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use memeinator::Config;
+use serde::{Deserialize, Serialize};
+
+/// What the editor remembers between sessions, kept next to the memecli config
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Settings {
+    /// The window size when the editor was last used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) window: Option<(f32, f32)>,
+    /// The local source templates were last saved to, the first one if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source: Option<String>,
+}
+
+impl Settings {
+    fn path() -> Option<PathBuf> {
+        Config::path()
+            .ok()
+            .map(|path| path.with_file_name("meme-bevy.json"))
+    }
+
+    /// The saved settings, or the defaults if there are none yet or they can't be read
+    pub(crate) fn load() -> Self {
+        let path = match Self::path() {
+            Some(path) if path.is_file() => path,
+            _ => return Self::default(),
+        };
+        match std::fs::read(&path).map(|bytes| serde_json::from_slice(&bytes)) {
+            Ok(Ok(settings)) => settings,
+            Ok(Err(e)) => {
+                println!("Ignoring broken settings at {}: {}", path.display(), e);
+                Self::default()
+            }
+            Err(e) => {
+                println!("Can't read settings at {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub(crate) fn save(&self) {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return,
+        };
+        let json = serde_json::to_string_pretty(self).unwrap();
+        if let Err(e) = std::fs::write(&path, json) {
+            println!("Can't save settings to {}: {}", path.display(), e);
+        }
+    }
+}
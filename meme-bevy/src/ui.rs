@@ -1,5 +1,8 @@
-use arboard::Clipboard;
+use std::{path::Path, time::Duration};
+
+use arboard::{Clipboard, ImageData};
 use bevy::{
+    ecs::system::CommandQueue,
     prelude::*,
     render2::{
         render_resource::{Extent3d, TextureDimension, TextureFormat},
@@ -7,15 +10,16 @@ use bevy::{
     },
     sprite2::{PipelinedSpriteBundle, Sprite},
 };
-use memeinator::{Config, MemeConfig, MemeField};
-use ui4::{
-    lens::{ComponentLens, LensObserver},
-    prelude::*,
-};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, ImageError, Rgba, RgbaImage};
+use memeinator::{Config, MemeConfig, MemeContent, MemeField, MemeTemplate, RenderContext};
+use ui4::prelude::*;
 
-use crate::{HandleSide, MemeSprite, OnePxHandle, RectHandle, TextRect, TextRects};
+use crate::{
+    clamp_edges, Caption, HandleSide, LoadedTemplate, MemeFrames, MemeSprite, OnePxHandle,
+    PreviewSprite, RectHandle, RectInputs, StoredField, TextRect, TextRects, BOX_COLOR,
+};
 
-use super::{MemeName, MemeTextColor};
+use super::{settings::Settings, GifPath, MemeName, MemeTextColor, SaveSource};
 
 pub fn root(ctx: Ctx) -> Ctx {
     ctx.with(UiColor(Color::BLACK))
@@ -45,52 +49,109 @@ fn text_size_repr_elem(
     index: IndexObserver,
     ctx: Ctx,
 ) -> Ctx {
-    let item_text = |f: &'static (dyn Fn(&TextRect) -> i32 + Send + Sync)| {
-        text(
-            entity
-                .map(|&entity: &Entity| component::<TextRect>(entity))
-                .flatten()
-                .map(
-                    move |text_item: FlattenReturn<LensObserver<ComponentLens<TextRect>>>| {
-                        f(&**text_item).to_string()
-                    },
-                ),
-        )
-    };
+    macro_rules! coord_box {
+        ($field:ident) => {
+            entity.map_child(|entity: Entity| {
+                move |ctx: &mut McCtx| {
+                    ctx.c(
+                        textbox(component::<RectInputs>(entity).lens(RectInputs::$field))
+                            .with(Width(Units::Pixels(100.))),
+                    );
+                }
+            })
+        };
+    }
+    let (min_x, min_y) = (coord_box!(min_x), coord_box!(min_y));
+    let (max_x, max_y) = (coord_box!(max_x), coord_box!(max_y));
+    let caption_box = entity.map_child(|entity: Entity| {
+        move |ctx: &mut McCtx| {
+            ctx.c(textbox(component::<Caption>(entity).lens(Caption::F0))
+                .with(Width(Units::Pixels(200.))));
+        }
+    });
     ctx.with(LayoutType::Row)
-        .with(Height(Units::Pixels(60.)))
+        .with(Height(Units::Pixels(90.)))
         .child(|ctx| {
             ctx.with(LayoutType::Column)
                 .with(Width(Units::Pixels(300.)))
                 .child(|ctx| {
                     ctx.with(LayoutType::Row)
                         .child(text("Top-left:").with(Width(Units::Pixels(100.))))
-                        .child(item_text(&(|text| text.min.x)).with(Width(Units::Pixels(100.))))
-                        .child(item_text(&(|text| text.min.y)).with(Width(Units::Pixels(100.))))
+                        .children(move |ctx: &mut McCtx| {
+                            ctx.dyn_group(min_x);
+                            ctx.dyn_group(min_y);
+                        })
                 })
                 .child(|ctx| {
                     ctx.with(LayoutType::Row)
                         .child(text("Bottom-right:").with(Width(Units::Pixels(100.))))
-                        .child(item_text(&(|text| text.max.x)).with(Width(Units::Pixels(100.))))
-                        .child(item_text(&(|text| text.max.y)).with(Width(Units::Pixels(100.))))
+                        .children(move |ctx: &mut McCtx| {
+                            ctx.dyn_group(max_x);
+                            ctx.dyn_group(max_y);
+                        })
+                })
+                .child(|ctx| {
+                    ctx.with(LayoutType::Row)
+                        .child(text("Caption:").with(Width(Units::Pixels(100.))))
+                        .children(move |ctx: &mut McCtx| {
+                            ctx.dyn_group(caption_box);
+                        })
                 })
         })
-        .child(
-            button("remove text")
+        .child(|ctx| {
+            ctx.with(LayoutType::Column)
                 .with(Width(Units::Pixels(60.)))
-                .with(Height(Units::Pixels(60.)))
-                .with(index.dedup().map(|&index: &usize| {
-                    OnClick::new(move |w| {
-                        let entity = w
-                            .get_resource_mut::<TextRects>()
-                            .unwrap()
-                            .rects
-                            .remove(index);
-
-                        w.entity_mut(entity).despawn_recursive();
-                    })
-                })),
-        )
+                .child(button("remove text").with(Height(Units::Pixels(45.))).with(
+                    index.dedup().map(|&index: &usize| {
+                        OnClick::new(move |w| {
+                            let entity = w
+                                .get_resource_mut::<TextRects>()
+                                .unwrap()
+                                .rects
+                                .remove(index);
+
+                            w.entity_mut(entity).despawn_recursive();
+                        })
+                    }),
+                ))
+                .child(
+                    button("duplicate")
+                        .with(Height(Units::Pixels(45.)))
+                        .with(index.dedup().map(|&index: &usize| {
+                            OnClick::new(move |w| duplicate_text_rect(w, index))
+                        })),
+                )
+        })
+}
+
+/// Copies the box at `index` in `TextRects`, a little down and to the right of it, or as far
+/// as the image edge allows
+fn duplicate_text_rect(w: &mut World, index: usize) {
+    let original = w.get_resource::<TextRects>().unwrap().rects[index];
+    let rect = w.get::<TextRect>(original).unwrap();
+    let offset = IVec2::new(10, 10);
+    let (mut min, mut max) = (rect.min + offset, rect.max + offset);
+    let mut meme_sprite = w.query_filtered::<(Entity, &Handle<Image>), With<MemeSprite>>();
+    let (root, image) = meme_sprite.single(w);
+    let images = w.get_resource::<Assets<Image>>().unwrap();
+    let size = images.get(image).unwrap().texture_descriptor.size;
+    clamp_edges(HandleSide::Both, &mut max.x, &mut min.x, size.width as i32);
+    clamp_edges(HandleSide::Both, &mut min.y, &mut max.y, size.height as i32);
+    let caption = w.get::<Caption>(original).unwrap().0.clone();
+    let stored = w
+        .get::<StoredField>(original)
+        .map(|stored| stored.0.clone());
+    let one_px = w.get_resource::<OnePxHandle>().unwrap().0.clone();
+
+    let mut queue = CommandQueue::default();
+    let mut commands = Commands::new(&mut queue, w);
+    let e = spawn_text_rect(&mut commands, &one_px, root, min, max);
+    commands.entity(e).insert(Caption(caption));
+    if let Some(stored) = stored {
+        commands.entity(e).insert(StoredField(stored));
+    }
+    queue.apply(w);
+    w.get_resource_mut::<TextRects>().unwrap().rects.push(e);
 }
 
 fn top_buttons(mut ctx: Ctx) -> Ctx {
@@ -107,64 +168,74 @@ fn top_buttons(mut ctx: Ctx) -> Ctx {
         Res<MemeTextColor>,
         ResMut<Assets<Image>>,
         ResMut<Config>,
-        Query<&Handle<Image>, With<MemeSprite>>,
-        Query<&TextRect>,
+        ResMut<LoadedTemplate>,
+        Res<SaveSource>,
+        ResMut<Settings>,
+        Query<(&Handle<Image>, Option<&MemeFrames>), With<MemeSprite>>,
+        Query<(&TextRect, Option<&StoredField>)>,
+    )>();
+
+    let mut preview_state = ctx.state::<(
+        Commands,
+        Res<TextRects>,
+        Res<MemeTextColor>,
+        Res<Config>,
+        Res<LoadedTemplate>,
+        ResMut<RenderContext>,
+        ResMut<Assets<Image>>,
+        Query<(Entity, &Handle<Image>, Option<&MemeFrames>), With<MemeSprite>>,
+        Query<(&TextRect, &Caption, Option<&StoredField>)>,
+        Query<Entity, With<PreviewSprite>>,
+    )>();
+
+    let mut render_copy_state = ctx.state::<(
+        ResMut<Clipboard>,
+        Res<TextRects>,
+        Res<MemeTextColor>,
+        Res<Config>,
+        Res<LoadedTemplate>,
+        ResMut<RenderContext>,
+        Res<Assets<Image>>,
+        Query<(&Handle<Image>, Option<&MemeFrames>), With<MemeSprite>>,
+        Query<(&TextRect, &Caption, Option<&StoredField>)>,
+    )>();
+
+    let mut load_template_state = ctx.state::<(
+        Commands,
+        Res<Config>,
+        Res<MemeName>,
+        Res<OnePxHandle>,
+        ResMut<TextRects>,
+        ResMut<MemeTextColor>,
+        ResMut<LoadedTemplate>,
+        ResMut<Assets<Image>>,
+        Query<(Entity, &mut Handle<Image>, &mut MemeSprite)>,
     )>();
 
     let mut clipboard_get_state = ctx.state::<(
+        Commands,
         ResMut<Clipboard>,
         ResMut<Assets<Image>>,
-        Query<(&mut Handle<Image>, &mut MemeSprite)>,
+        Query<(Entity, &mut Handle<Image>, &mut MemeSprite)>,
+    )>();
+
+    let mut gif_load_state = ctx.state::<(
+        Commands,
+        Res<GifPath>,
+        ResMut<Assets<Image>>,
+        Query<(Entity, &mut Handle<Image>, &mut MemeSprite)>,
     )>();
 
     ctx.child(button("New Text").with(OnClick::new(move |world| {
         let (mut commands, image, mut rects, q) = new_text_state.get_mut(world);
         let root = q.single();
-        let e = commands
-            .spawn_bundle(PipelinedSpriteBundle {
-                texture: image.0.clone(),
-                sprite: Sprite {
-                    color: Color::rgba(0.8, 0.8, 0.8, 0.4),
-                    ..Default::default()
-                },
-                ..Default::default()
-            })
-            .insert(TextRect {
-                min: IVec2::new(100, 100),
-                max: IVec2::new(200, 200),
-            })
-            .with_children(|parent| {
-                let e = parent.parent_entity();
-                let list = {
-                    use HandleSide::*;
-                    [
-                        (Negative, Negative),
-                        (Positive, Positive),
-                        (Negative, Positive),
-                        (Positive, Negative),
-                        (Positive, Indifferent),
-                        (Negative, Indifferent),
-                        (Indifferent, Positive),
-                        (Indifferent, Negative),
-                        (Both, Both),
-                    ]
-                };
-                for (x, y) in list {
-                    parent
-                        .spawn_bundle(PipelinedSpriteBundle {
-                            texture: image.0.clone(),
-                            sprite: Sprite {
-                                color: Color::BLUE,
-                                custom_size: Some(Vec2::new(20., 20.)),
-                                ..Default::default()
-                            },
-                            ..Default::default()
-                        })
-                        .insert(RectHandle { rect: e, x, y });
-                }
-            })
-            .id();
-        commands.entity(root).push_children(&[e]);
+        let e = spawn_text_rect(
+            &mut commands,
+            &image.0,
+            root,
+            IVec2::new(100, 100),
+            IVec2::new(200, 200),
+        );
         rects.rects.push(e);
 
         new_text_state.apply(world);
@@ -176,61 +247,98 @@ fn top_buttons(mut ctx: Ctx) -> Ctx {
             .child(textbox(res::<MemeName>().lens(MemeName::F0)).with(Width(Units::Pixels(260.))))
     })
     .child(|ctx| {
-        fn rb(color: MemeTextColor, t: &'static str) -> impl FnOnce(Ctx) -> Ctx {
-            move |ctx: Ctx| {
-                ctx.with(LayoutType::Row)
-                    .with(Width(Units::Pixels(130.)))
-                    .child(text(t).with(Width(Units::Pixels(100.))))
-                    .child(
-                        radio_button(color, res::<MemeTextColor>()).with(Width(Units::Pixels(30.))),
-                    )
-            }
+        ctx.with(LayoutType::Row)
+            .with(Height(Units::Pixels(30.)))
+            .child(text("Save to source").with(Width(Units::Pixels(100.))))
+            .child(
+                textbox(res::<SaveSource>().lens(SaveSource::F0)).with(Width(Units::Pixels(260.))),
+            )
+    })
+    .child(|ctx| {
+        fn preset(hex: &'static str, t: &'static str) -> impl FnOnce(Ctx) -> Ctx {
+            button(t)
+                .with(Width(Units::Pixels(50.)))
+                .with(OnClick::new(move |w| {
+                    w.get_resource_mut::<MemeTextColor>().unwrap().0 = hex.to_owned();
+                }))
         }
         ctx.with(LayoutType::Row)
             .with(Height(Units::Pixels(30.)))
             .child(text("Text Color").with(Width(Units::Pixels(100.))))
-            .child(rb(MemeTextColor::Black, "Black"))
-            .child(rb(MemeTextColor::White, "White"))
+            .child(
+                textbox(res::<MemeTextColor>().lens(MemeTextColor::F0))
+                    .with(Width(Units::Pixels(120.))),
+            )
+            .child(|ctx| {
+                ctx.with(Width(Units::Pixels(30.)))
+                    .with(res::<MemeTextColor>().map(|color: &MemeTextColor| {
+                        // an invalid code shows as no color at all
+                        let [r, g, b, a] = color.rgba().unwrap_or([0.; 4]);
+                        UiColor(Color::rgba(r, g, b, a))
+                    }))
+            })
+            .child(preset("#000000", "Black"))
+            .child(preset("#ffffff", "White"))
     })
     .child(button("Save Template").with(OnClick::new(move |w| {
-        let (rects, mut meme_name, color, images, config, meme_sprite, rects_q) =
-            save_meme_state.get_mut(w);
+        let (
+            rects,
+            mut meme_name,
+            color,
+            images,
+            config,
+            mut loaded,
+            source,
+            mut settings,
+            meme_sprite,
+            rects_q,
+        ) = save_meme_state.get_mut(w);
 
         if meme_name.0.is_empty() {
             print!("Can't save template without name");
             return;
         }
+        let color = match color.rgba() {
+            Some(color) => color,
+            None => {
+                println!("{} isn't a hex color", color.0);
+                return;
+            }
+        };
 
-        let image = images.get(meme_sprite.single()).unwrap();
+        let (handle, frames) = meme_sprite.single();
+        let image = template_image(&images, handle, frames);
         let text = rects
             .rects
             .iter()
             .map(|&rect| rects_q.get(rect).unwrap())
-            .map(|rect| MemeField {
-                min: (
-                    rect.min.x.min(rect.max.x) as u32,
-                    rect.min.y.min(rect.max.y) as u32,
-                ),
-                max: (
-                    rect.min.x.max(rect.max.x) as u32,
-                    rect.min.y.max(rect.max.y) as u32,
-                ),
-            })
+            .map(|(rect, stored)| rect_field(rect, stored))
             .collect();
 
+        // an opened template keeps the settings the editor doesn't show, and is replaced when
+        // saved under the same name
+        let (base, overwrite) = match &loaded.0 {
+            Some((name, config)) => (config.clone(), *name == meme_name.0),
+            None => (MemeConfig::default(), false),
+        };
+
         let width = image.texture_descriptor.size.width;
         let height = image.texture_descriptor.size.height;
-        let error = config.write_template(
+        let meme_config = MemeConfig {
+            color: Some(color),
+            text,
+            // panels were already laid out into the saved image
+            panels: None,
+            ..base
+        };
+        let source = (!source.0.is_empty()).then(|| source.0.as_str());
+        let error = config.write_template_in(
+            source,
+            overwrite,
             &image.data,
             width,
             height,
-            MemeConfig {
-                color: Some(match *color {
-                    MemeTextColor::Black => [0., 0., 0., 1.],
-                    MemeTextColor::White => [1., 1., 1., 1.],
-                }),
-                text,
-            },
+            meme_config,
             &meme_name.0,
         );
 
@@ -238,6 +346,11 @@ fn top_buttons(mut ctx: Ctx) -> Ctx {
             println!("{:#}", e);
         } else {
             meme_name.0.clear();
+            loaded.0 = None;
+            if settings.source.as_deref() != source {
+                settings.source = source.map(String::from);
+                settings.save();
+            }
             w.resource_scope(|world, mut rects: Mut<TextRects>| {
                 for &rect in &*rects.rects {
                     world.entity_mut(rect).despawn_recursive();
@@ -246,12 +359,143 @@ fn top_buttons(mut ctx: Ctx) -> Ctx {
             });
         }
     })))
+    .child(button("Toggle Preview").with(OnClick::new(move |world| {
+        let (
+            mut commands,
+            rects,
+            color,
+            config,
+            loaded,
+            mut render_ctx,
+            mut images,
+            meme_sprite,
+            rects_q,
+            preview_q,
+        ) = preview_state.get_mut(world);
+
+        if let Ok(preview) = preview_q.get_single() {
+            commands.entity(preview).despawn_recursive();
+        } else {
+            let (root, handle, frames) = meme_sprite.single();
+            let image = template_image(&images, handle, frames);
+            let boxes = rects.rects.iter().map(|&rect| rects_q.get(rect).unwrap());
+            match render_edited(image, boxes, &color, &config, &loaded, &mut render_ctx) {
+                Ok(rendered) => {
+                    let size = image.texture_descriptor.size;
+                    let texture = images.add(Image::new(
+                        size,
+                        TextureDimension::D2,
+                        rendered.into_raw(),
+                        TextureFormat::Rgba8UnormSrgb,
+                    ));
+                    let preview = commands
+                        .spawn_bundle(PipelinedSpriteBundle {
+                            texture,
+                            // over the boxes and their handles
+                            transform: Transform::from_xyz(0., 0., 5.),
+                            ..Default::default()
+                        })
+                        .insert(PreviewSprite)
+                        .id();
+                    commands.entity(root).push_children(&[preview]);
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+        preview_state.apply(world);
+    })))
+    .child(button("Render & Copy").with(OnClick::new(move |world| {
+        let (
+            mut clipboard,
+            rects,
+            color,
+            config,
+            loaded,
+            mut render_ctx,
+            images,
+            meme_sprite,
+            rects_q,
+        ) = render_copy_state.get_mut(world);
+
+        let (handle, frames) = meme_sprite.single();
+        let image = template_image(&images, handle, frames);
+        let boxes = rects.rects.iter().map(|&rect| rects_q.get(rect).unwrap());
+        match render_edited(image, boxes, &color, &config, &loaded, &mut render_ctx) {
+            Ok(rendered) => {
+                let (width, height) = rendered.dimensions();
+                let copied = clipboard.set_image(ImageData {
+                    width: width as usize,
+                    height: height as usize,
+                    bytes: rendered.into_raw().into(),
+                });
+                if let Err(e) = copied {
+                    println!("Can't copy the meme: {}", e);
+                }
+            }
+            Err(e) => println!("{}", e),
+        }
+    })))
+    .child(button("Open Template").with(OnClick::new(move |world| {
+        let (
+            mut commands,
+            config,
+            meme_name,
+            one_px,
+            mut rects,
+            mut color,
+            mut loaded,
+            mut images,
+            mut q,
+        ) = load_template_state.get_mut(world);
+
+        match config.get_meme_template(&meme_name.0) {
+            Ok(template) => {
+                for &rect in &*rects.rects {
+                    commands.entity(rect).despawn_recursive();
+                }
+                rects.rects.clear();
+
+                let (root, mut handle, mut koe) = q.single_mut();
+                let (width, height) = template.dimensions();
+                koe.0 = width as f32 / height as f32;
+                commands.entity(root).remove::<MemeFrames>();
+                *handle = images.add(Image::new(
+                    Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    TextureDimension::D2,
+                    template.image().as_raw().clone(),
+                    TextureFormat::Rgba8UnormSrgb,
+                ));
+
+                if let Some(text_color) = template.text_color() {
+                    *color = MemeTextColor::from_rgba(text_color.0);
+                }
+
+                for field in template.fields() {
+                    let min = IVec2::new(field.min.0 as i32, field.min.1 as i32);
+                    let max = IVec2::new(field.max.0 as i32, field.max.1 as i32);
+                    let e = spawn_text_rect(&mut commands, &one_px.0, root, min, max);
+                    commands.entity(e).insert(StoredField(field.clone()));
+                    rects.rects.push(e);
+                }
+
+                loaded.0 = Some((meme_name.0.clone(), template.config().clone()));
+            }
+            Err(e) => println!("Can't open {}: {:#}", meme_name.0, e),
+        }
+        load_template_state.apply(world);
+    })))
     .child(
         button("Get template from clipboard").with(OnClick::new(move |world| {
-            let (mut clipboard, mut images, mut q) = clipboard_get_state.get_mut(world);
+            let (mut commands, mut clipboard, mut images, mut q) =
+                clipboard_get_state.get_mut(world);
             if let Ok(img) = clipboard.get_image() {
-                let (mut handle, mut koe) = q.single_mut();
+                let (entity, mut handle, mut koe) = q.single_mut();
                 koe.0 = img.width as f32 / img.height as f32;
+                commands.entity(entity).remove::<MemeFrames>();
 
                 *handle = images.add(Image::new(
                     Extent3d {
@@ -264,8 +508,202 @@ fn top_buttons(mut ctx: Ctx) -> Ctx {
                     TextureFormat::Rgba8UnormSrgb,
                 ));
             }
+            clipboard_get_state.apply(world);
         })),
     )
+    .child(|ctx| {
+        ctx.with(LayoutType::Row)
+            .with(Height(Units::Pixels(30.)))
+            .child(text("GIF path").with(Width(Units::Pixels(100.))))
+            .child(textbox(res::<GifPath>().lens(GifPath::F0)).with(Width(Units::Pixels(260.))))
+    })
+    .child(
+        button("Load animated template").with(OnClick::new(move |world| {
+            let (mut commands, path, mut images, mut q) = gif_load_state.get_mut(world);
+            match load_gif_frames(&path.0) {
+                Ok(frames) if !frames.is_empty() => {
+                    let (entity, mut handle, mut koe) = q.single_mut();
+                    let (width, height) = frames[0].0.dimensions();
+                    koe.0 = width as f32 / height as f32;
+                    let frames: Vec<_> = frames
+                        .into_iter()
+                        .map(|(frame, delay)| {
+                            let (width, height) = frame.dimensions();
+                            let image = images.add(Image::new(
+                                Extent3d {
+                                    width,
+                                    height,
+                                    depth_or_array_layers: 1,
+                                },
+                                TextureDimension::D2,
+                                frame.into_raw(),
+                                TextureFormat::Rgba8UnormSrgb,
+                            ));
+                            (image, delay)
+                        })
+                        .collect();
+                    *handle = frames[0].0.clone();
+                    commands.entity(entity).insert(MemeFrames::new(frames));
+                }
+                Ok(_) => println!("{} has no frames", path.0),
+                Err(e) => println!("Can't load {}: {}", path.0, e),
+            }
+            gif_load_state.apply(world);
+        })),
+    )
+}
+
+/// The template image being edited. Animated templates are saved and rendered as their first
+/// frame.
+fn template_image<'a>(
+    images: &'a Assets<Image>,
+    handle: &Handle<Image>,
+    frames: Option<&MemeFrames>,
+) -> &'a Image {
+    match frames {
+        Some(frames) => images.get(&frames.frames[0].0).unwrap(),
+        None => images.get(handle).unwrap(),
+    }
+}
+
+/// Renders the template being edited like `meme-cli generate` would, with each box's caption
+/// or a placeholder for boxes without one
+fn render_edited<'a>(
+    image: &Image,
+    boxes: impl Iterator<Item = (&'a TextRect, &'a Caption, Option<&'a StoredField>)>,
+    color: &MemeTextColor,
+    config: &Config,
+    loaded: &LoadedTemplate,
+    render_ctx: &mut RenderContext,
+) -> Result<RgbaImage, String> {
+    let color = color
+        .rgba()
+        .ok_or_else(|| format!("{} isn't a hex color", color.0))?;
+    let size = image.texture_descriptor.size;
+    let image = RgbaImage::from_raw(size.width, size.height, image.data.clone()).unwrap();
+
+    let (text, content) = boxes
+        .enumerate()
+        .map(|(i, (rect, caption, stored))| {
+            let content = if caption.0.is_empty() {
+                format!("Sample text {}", i + 1)
+            } else {
+                caption.0.clone()
+            };
+            (rect_field(rect, stored), MemeContent::Text(content))
+        })
+        .unzip();
+    let meme_config = MemeConfig {
+        text,
+        panels: None,
+        ..loaded
+            .0
+            .as_ref()
+            .map(|(_, config)| config.clone())
+            .unwrap_or_default()
+    };
+
+    let template = MemeTemplate::from_image(image, meme_config).map_err(|e| format!("{:#}", e))?;
+    let watermark = template
+        .watermark()
+        .unwrap_or_else(|| Some(config.watermark()));
+    Ok(template
+        .with_linear_blending(config.linear_blending())
+        .render_with(
+            render_ctx,
+            Rgba(color.map(|x| (x * u8::MAX as f32).round() as u8)),
+            content,
+            600.,
+            watermark,
+            config.watermark_size_fraction(),
+        ))
+}
+
+/// The field a box is saved as
+fn rect_field(rect: &TextRect, stored: Option<&StoredField>) -> MemeField {
+    MemeField {
+        min: (
+            rect.min.x.min(rect.max.x) as u32,
+            rect.min.y.min(rect.max.y) as u32,
+        ),
+        max: (
+            rect.min.x.max(rect.max.x) as u32,
+            rect.min.y.max(rect.max.y) as u32,
+        ),
+        ..stored.map(|stored| stored.0.clone()).unwrap_or_default()
+    }
+}
+
+/// Spawns a text box with its resize handles under the meme sprite `root`
+pub(crate) fn spawn_text_rect(
+    commands: &mut Commands,
+    one_px: &Handle<Image>,
+    root: Entity,
+    min: IVec2,
+    max: IVec2,
+) -> Entity {
+    let e = commands
+        .spawn_bundle(PipelinedSpriteBundle {
+            texture: one_px.clone(),
+            sprite: Sprite {
+                color: BOX_COLOR,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(TextRect { min, max })
+        .insert(RectInputs::default())
+        .insert(Caption::default())
+        .with_children(|parent| {
+            let e = parent.parent_entity();
+            let list = {
+                use HandleSide::*;
+                [
+                    (Negative, Negative),
+                    (Positive, Positive),
+                    (Negative, Positive),
+                    (Positive, Negative),
+                    (Positive, Indifferent),
+                    (Negative, Indifferent),
+                    (Indifferent, Positive),
+                    (Indifferent, Negative),
+                    (Both, Both),
+                ]
+            };
+            for (x, y) in list {
+                parent
+                    .spawn_bundle(PipelinedSpriteBundle {
+                        texture: one_px.clone(),
+                        sprite: Sprite {
+                            color: Color::BLUE,
+                            custom_size: Some(Vec2::new(20., 20.)),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    })
+                    .insert(RectHandle { rect: e, x, y });
+            }
+        })
+        .id();
+    commands.entity(root).push_children(&[e]);
+    e
+}
+
+/// The frames of the GIF at `path`, and how long each is shown
+pub(crate) fn load_gif_frames(
+    path: impl AsRef<Path>,
+) -> Result<Vec<(RgbaImage, Duration)>, ImageError> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let frames = GifDecoder::new(file)?.into_frames().collect_frames()?;
+    Ok(frames
+        .into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            // like browsers, don't let a missing delay spin the animation as fast as possible
+            let delay = Duration::from_millis((numer / denom.max(1)) as u64);
+            (frame.into_buffer(), delay.max(Duration::from_millis(20)))
+        })
+        .collect())
 }
 
 fn separator(ctx: Ctx) -> Ctx {
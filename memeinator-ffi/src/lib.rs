@@ -0,0 +1,122 @@
+//! A C ABI for rendering memes from other languages.
+//!
+//! Buffers returned by [`memeinator_render`] must be released with [`memeinator_free`].
+
+use std::{ffi::CStr, os::raw::c_char, panic, ptr};
+
+use image::Rgba;
+use memeinator::{Config, MemeContent};
+
+/// Tightly packed RGBA8 pixels, `width * height * 4` bytes long.
+/// A null `data` pointer signals that rendering failed.
+#[repr(C)]
+pub struct MemeBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MemeBuffer {
+    fn null() -> Self {
+        Self {
+            data: ptr::null_mut(),
+            len: 0,
+            width: 0,
+            height: 0,
+        }
+    }
+}
+
+/// Renders a template from the user's configured sources, in the template's text color or black.
+/// The template's watermark is used if it sets one, the configured one otherwise.
+///
+/// # Safety
+///
+/// `template` must be a valid nul-terminated string, and `inputs` must point to
+/// `input_count` valid nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn memeinator_render(
+    template: *const c_char,
+    inputs: *const *const c_char,
+    input_count: usize,
+) -> MemeBuffer {
+    // unwinding into the caller's frames is undefined behavior, so a panic is a failed render
+    panic::catch_unwind(|| render(template, inputs, input_count))
+        .unwrap_or_else(|_| MemeBuffer::null())
+}
+
+unsafe fn render(
+    template: *const c_char,
+    inputs: *const *const c_char,
+    input_count: usize,
+) -> MemeBuffer {
+    if template.is_null() || (inputs.is_null() && input_count > 0) {
+        return MemeBuffer::null();
+    }
+    let template = match CStr::from_ptr(template).to_str() {
+        Ok(template) => template,
+        Err(_) => return MemeBuffer::null(),
+    };
+    let mut content = Vec::with_capacity(input_count);
+    for i in 0..input_count {
+        let input = *inputs.add(i);
+        if input.is_null() {
+            return MemeBuffer::null();
+        }
+        content.push(MemeContent::Text(
+            CStr::from_ptr(input).to_string_lossy().into_owned(),
+        ));
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(_) => return MemeBuffer::null(),
+    };
+    let meme = match config.get_meme_template(template) {
+        Ok(meme) => meme,
+        Err(_) => return MemeBuffer::null(),
+    };
+    let mut ctx = match config.render_context() {
+        Ok(ctx) => ctx,
+        Err(_) => return MemeBuffer::null(),
+    };
+    let color = meme.text_color().unwrap_or(Rgba([0, 0, 0, 255]));
+    let watermark = meme.watermark().map(|w| w.map(String::from));
+    let rendered = meme.render_with(
+        &mut ctx,
+        color,
+        content,
+        600.,
+        watermark
+            .as_ref()
+            .map(|w| w.as_deref())
+            .unwrap_or_else(|| Some(config.watermark())),
+        config.watermark_size_fraction(),
+    );
+
+    let (width, height) = rendered.dimensions();
+    let bytes = rendered.into_raw().into_boxed_slice();
+    let len = bytes.len();
+    MemeBuffer {
+        data: Box::into_raw(bytes) as *mut u8,
+        len,
+        width,
+        height,
+    }
+}
+
+/// Frees a buffer returned by [`memeinator_render`].
+///
+/// # Safety
+///
+/// `buffer` must come from [`memeinator_render`] and must not be freed twice.
+#[no_mangle]
+pub unsafe extern "C" fn memeinator_free(buffer: MemeBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
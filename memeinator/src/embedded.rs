@@ -0,0 +1,104 @@
+//! Templates compiled into the binary, served by [`MemeSource::Embedded`](crate::MemeSource).
+//! Without the `embedded` feature there are none.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "embedded")]
+use crate::{is_template_image, load_panel_template, load_template, parse_template_config};
+use crate::{MemeError, MemeTemplate, TemplateInfo};
+
+#[cfg(feature = "embedded")]
+static TEMPLATES: include_dir::Dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/embedded");
+
+/// Where embedded templates claim to live, for messages and `TemplateInfo`
+pub(crate) fn template_path(name: &str) -> PathBuf {
+    Path::new("<embedded>").join(name)
+}
+
+#[cfg(feature = "embedded")]
+pub(crate) fn template_infos() -> Vec<TemplateInfo> {
+    TEMPLATES
+        .dirs()
+        .filter_map(|dir| dir.path().to_str())
+        .map(|name| TemplateInfo {
+            name: name.to_owned(),
+            path: template_path(name),
+            animated: TEMPLATES
+                .get_file(Path::new(name).join("animated.gif"))
+                .is_some(),
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "embedded"))]
+pub(crate) fn template_infos() -> Vec<TemplateInfo> {
+    vec![]
+}
+
+/// Loads an embedded template, or `None` if there's none called `name`
+#[cfg(feature = "embedded")]
+pub(crate) fn get_template(name: &str) -> Option<Result<MemeTemplate, MemeError>> {
+    let dir = TEMPLATES.get_dir(name)?;
+    let config = dir.get_file(Path::new(name).join("config.json"))?;
+    let mut config = match parse_template_config(&template_path(name), config.contents()) {
+        Ok(config) => config,
+        Err(e) => return Some(Err(e)),
+    };
+    if let Some(layout) = config.panels.take() {
+        return Some(load_panel_template(
+            &template_path(name),
+            config,
+            layout,
+            |file| read_file(name, file),
+        ));
+    }
+    let image = dir
+        .get_file(Path::new(name).join("image.png"))
+        .or_else(|| dir.files().find(|file| is_template_image(file.path())))?;
+    Some(load_template(
+        &template_path(name),
+        config,
+        image.contents(),
+    ))
+}
+
+#[cfg(not(feature = "embedded"))]
+pub(crate) fn get_template(_name: &str) -> Option<Result<MemeTemplate, MemeError>> {
+    None
+}
+
+/// Reads `file` from the directory of the embedded template called `name`
+#[cfg(feature = "embedded")]
+pub(crate) fn read_file(name: &str, file: &str) -> Result<Vec<u8>, MemeError> {
+    TEMPLATES
+        .get_file(Path::new(name).join(file))
+        .map(|file| file.contents().to_vec())
+        .ok_or_else(|| MemeError::SourceIo {
+            path: template_path(name).join(file),
+            source: io::ErrorKind::NotFound.into(),
+        })
+}
+
+#[cfg(not(feature = "embedded"))]
+pub(crate) fn read_file(name: &str, file: &str) -> Result<Vec<u8>, MemeError> {
+    Err(MemeError::SourceIo {
+        path: template_path(name).join(file),
+        source: io::ErrorKind::NotFound.into(),
+    })
+}
+
+/// The `config.json` of an embedded template, or `None` if there's none called `name`
+#[cfg(feature = "embedded")]
+pub(crate) fn get_config(name: &str) -> Option<&'static [u8]> {
+    TEMPLATES
+        .get_file(Path::new(name).join("config.json"))
+        .map(|file| file.contents())
+}
+
+#[cfg(not(feature = "embedded"))]
+pub(crate) fn get_config(_name: &str) -> Option<&'static [u8]> {
+    None
+}
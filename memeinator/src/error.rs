@@ -0,0 +1,60 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MemeError {
+    #[error("Can't find template {0}")]
+    TemplateNotFound(String),
+    #[error("Can't find template {0} in source {1}")]
+    TemplateNotInSource(String, String),
+    #[error("No source called {0}")]
+    SourceNotFound(String),
+    #[error("can't parse template name as utf8")]
+    InvalidTemplateName,
+    #[error("Source alias {0} isn't a plain directory name")]
+    InvalidAlias(String),
+    #[error("No local sources configured")]
+    NoLocalSource,
+    #[error("Source {0} isn't a local directory, templates can only be written to those")]
+    NotLocalSource(String),
+    #[error("{0} dir not found, set MEMECLI_CONFIG_DIR or MEMECLI_CACHE_DIR to pick one")]
+    DirNotFound(&'static str),
+    #[error("Cannot access {}", path.display())]
+    SourceIo {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{0}")]
+    Git(String),
+    #[error("Cannot decode or encode the template image")]
+    Decode(#[from] image::ImageError),
+    #[error("The configuration file is broken")]
+    ConfigParse(#[source] serde_json::Error),
+    #[error("The configuration file is broken")]
+    TomlConfigParse(#[source] toml::de::Error),
+    #[error("The template configuration at {} is broken", path.display())]
+    TemplateConfig {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("The template at {} is invalid: {reason}", path.display())]
+    InvalidTemplate { path: PathBuf, reason: String },
+    #[error("The template is invalid: {0}")]
+    InvalidUnsavedTemplate(String),
+    #[error("The font at {} is invalid: {reason}", path.display())]
+    InvalidFont { path: PathBuf, reason: &'static str },
+    #[error("Unknown filter {0}, expected one of grayscale, sepia, invert or deepfry")]
+    UnknownFilter(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl MemeError {
+    pub(crate) fn source_io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| MemeError::SourceIo { path, source }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{io::Cursor, str::FromStr};
+
+use image::{
+    codecs::jpeg::JpegEncoder, imageops, io::Reader as ImageReader, GrayImage, ImageFormat, Luma,
+    Rgba, RgbaImage,
+};
+
+use crate::MemeError;
+
+/// A post-processing effect applied to a finished meme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Grayscale,
+    Sepia,
+    Invert,
+    /// Oversaturated, oversharpened and crushed by jpeg compression
+    Deepfry,
+}
+
+impl FromStr for Filter {
+    type Err = MemeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grayscale" => Ok(Filter::Grayscale),
+            "sepia" => Ok(Filter::Sepia),
+            "invert" => Ok(Filter::Invert),
+            "deepfry" => Ok(Filter::Deepfry),
+            _ => Err(MemeError::UnknownFilter(s.to_owned())),
+        }
+    }
+}
+
+impl Filter {
+    pub fn apply(self, mut img: RgbaImage) -> Result<RgbaImage, MemeError> {
+        match self {
+            Filter::Grayscale => map_rgb(&mut img, |[r, g, b]| {
+                let l = luma([r, g, b]);
+                [l, l, l]
+            }),
+            Filter::Sepia => map_rgb(&mut img, |[r, g, b]| {
+                [
+                    0.393 * r + 0.769 * g + 0.189 * b,
+                    0.349 * r + 0.686 * g + 0.168 * b,
+                    0.272 * r + 0.534 * g + 0.131 * b,
+                ]
+            }),
+            Filter::Invert => imageops::invert(&mut img),
+            Filter::Deepfry => return deepfry(img),
+        }
+        Ok(img)
+    }
+}
+
+/// Reduces a meme to pure black and white for 1-bit displays and printers, dithering
+/// Floyd–Steinberg style to keep the grays. Transparency shows as white, like on paper.
+pub fn mono(img: &RgbaImage) -> GrayImage {
+    let mut gray = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let Rgba([r, g, b, a]) = *img.get_pixel(x, y);
+        let alpha = a as f32 / u8::MAX as f32;
+        let l = luma([r as f32, g as f32, b as f32]) * alpha + u8::MAX as f32 * (1. - alpha);
+        Luma([l.clamp(0., 255.) as u8])
+    });
+    imageops::dither(&mut gray, &imageops::BiLevel);
+    gray
+}
+
+fn luma([r, g, b]: [f32; 3]) -> f32 {
+    0.299 * r + 0.587 * g + 0.114 * b
+}
+
+/// Maps the color channels of every pixel, leaving alpha alone
+fn map_rgb(img: &mut RgbaImage, f: impl Fn([f32; 3]) -> [f32; 3]) {
+    for Rgba([r, g, b, _]) in img.pixels_mut() {
+        let [nr, ng, nb] = f([*r as f32, *g as f32, *b as f32]);
+        *r = nr.clamp(0., 255.) as u8;
+        *g = ng.clamp(0., 255.) as u8;
+        *b = nb.clamp(0., 255.) as u8;
+    }
+}
+
+fn deepfry(mut img: RgbaImage) -> Result<RgbaImage, MemeError> {
+    map_rgb(&mut img, |rgb| {
+        let l = luma(rgb);
+        // push every channel away from gray, then stretch the contrast
+        rgb.map(|c| (l + (c - l) * 2.5 - 128.) * 1.4 + 128.)
+    });
+    let img = imageops::unsharpen(&img, 2., 10);
+
+    let (width, height) = img.dimensions();
+    let rgb = image::DynamicImage::ImageRgba8(img.clone()).to_rgb8();
+    let mut jpeg = vec![];
+    JpegEncoder::new_with_quality(&mut jpeg, 10).encode(
+        &rgb,
+        width,
+        height,
+        image::ColorType::Rgb8,
+    )?;
+    let crushed = ImageReader::with_format(Cursor::new(jpeg), ImageFormat::Jpeg)
+        .decode()?
+        .to_rgb8();
+
+    // jpeg has no alpha, so keep the original transparency
+    let mut fried = img;
+    for (out, px) in fried.pixels_mut().zip(crushed.pixels()) {
+        out.0[..3].copy_from_slice(&px.0);
+    }
+    Ok(fried)
+}
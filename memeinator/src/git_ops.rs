@@ -1,28 +1,155 @@
 use std::{
+    fs,
     path::Path,
-    process::{Command, Stdio},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Error};
+use crate::MemeError;
 
-pub(crate) fn update_repo(path: &Path) -> Result<(), Error> {
-    Command::new("git")
+pub(crate) fn update_repo(path: &Path, timeout: Duration) -> Result<(), MemeError> {
+    log::debug!("Running git pull in {}", path.display());
+    let child = Command::new("git")
         .args(["pull"])
         .current_dir(path)
         .stdout(Stdio::inherit())
-        .spawn()?
-        .wait()?
-        .success()
-        .then(|| ())
-        .ok_or_else(|| anyhow!("Git error updating repository at {:?}", path))
+        .spawn()?;
+    wait_with_timeout(child, timeout)?
+        .then_some(())
+        .ok_or_else(|| MemeError::Git(format!("Git error updating repository at {:?}", path)))
 }
-pub(crate) fn clone_repo(path: &Path, url: &str) -> Result<(), Error> {
-    Command::new("git")
+pub(crate) fn clone_repo(path: &Path, url: &str, timeout: Duration) -> Result<(), MemeError> {
+    log::debug!("Running git clone {} in {}", url, path.display());
+    let child = Command::new("git")
         .args(["clone", url, "."])
         .current_dir(path)
-        .spawn()?
-        .wait()?
+        .spawn()?;
+    let result = wait_with_timeout(child, timeout).and_then(|success| {
+        success
+            .then_some(())
+            .ok_or_else(|| MemeError::Git(format!("Git error cloning repository into {:?}", path)))
+    });
+    if result.is_err() {
+        remove_partial_clone(path);
+    }
+    result
+}
+
+/// Clones only the repository's trees and top-level files. Template files are fetched as
+/// they're checked out with [`checkout_dir`].
+pub(crate) fn clone_repo_sparse(
+    path: &Path,
+    url: &str,
+    timeout: Duration,
+) -> Result<(), MemeError> {
+    log::debug!("Running sparse git clone {} in {}", url, path.display());
+    let child = Command::new("git")
+        .args(["clone", "--filter=blob:none", "--sparse", url, "."])
+        .current_dir(path)
+        .spawn()?;
+    let result = wait_with_timeout(child, timeout).and_then(|success| {
+        success.then_some(()).ok_or_else(|| {
+            MemeError::Git(format!(
+                "Git error sparse cloning repository into {:?}",
+                path
+            ))
+        })
+    });
+    if result.is_err() {
+        remove_partial_clone(path);
+    }
+    result
+}
+
+/// Removes what a failed or timed out clone left behind, so the next update clones again
+/// instead of pulling into a broken repository
+fn remove_partial_clone(path: &Path) {
+    log::debug!("Removing the partial clone in {}", path.display());
+    if let Err(e) = fs::remove_dir_all(path) {
+        log::warn!(
+            "Can't remove the partial clone in {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Adds the directory `dir` to a sparse checkout, fetching its files
+pub(crate) fn checkout_dir(path: &Path, dir: &str, timeout: Duration) -> Result<(), MemeError> {
+    log::debug!(
+        "Adding {} to the sparse checkout in {}",
+        dir,
+        path.display()
+    );
+    let child = Command::new("git")
+        .args(["sparse-checkout", "add", "--", dir])
+        .current_dir(path)
+        .spawn()?;
+    if wait_with_timeout(child, timeout)? {
+        Ok(())
+    } else {
+        Err(MemeError::Git(format!(
+            "Git error checking out {} in {:?}",
+            dir, path
+        )))
+    }
+}
+
+/// The top-level directories of the repository's current commit, whether they're checked out
+/// or not
+pub(crate) fn list_dirs(path: &Path) -> Result<Vec<String>, MemeError> {
+    let output = Command::new("git")
+        .args(["ls-tree", "-d", "-z", "--name-only", "HEAD"])
+        .current_dir(path)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(MemeError::Git(format!(
+            "Git error listing directories in {:?}",
+            path
+        )));
+    }
+    Ok(output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+/// The URL the repository at `path` was cloned from, if git can tell
+pub(crate) fn origin_url(path: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["config", "--get", "remote.origin.url"])
+        .current_dir(path)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
         .success()
-        .then(|| ())
-        .ok_or_else(|| anyhow!("Git error cloning repository into {:?}", path))
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Waits for git to finish, killing it if it takes longer than `timeout`.
+/// Returns whether it succeeded.
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> Result<bool, MemeError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            log::debug!("git finished with {}", status);
+            return Ok(status.success());
+        }
+        if Instant::now() >= deadline {
+            log::debug!("git is still running after {:?}, killing it", timeout);
+            child.kill()?;
+            child.wait()?;
+            return Err(MemeError::Git(format!(
+                "Git timed out after {} seconds",
+                timeout.as_secs()
+            )));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
 }
@@ -0,0 +1,51 @@
+//! Lets hand-edited JSON configs have comments and trailing commas, by blanking them out
+//! before the JSON is parsed. Blanking rather than removing keeps error positions accurate.
+
+/// Replaces `//` and `/* */` comments, and commas right before a `}` or `]`, with spaces
+pub(crate) fn strip(src: &str) -> String {
+    let mut out: Vec<char> = src.chars().collect();
+    // where the last comma outside a string is, until something other than whitespace follows
+    let mut pending_comma = None;
+    let mut in_string = false;
+    let mut i = 0;
+    while i < out.len() {
+        let c = out[i];
+        if in_string {
+            match c {
+                '\\' => i += 1,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '/' && out.get(i + 1) == Some(&'/') {
+            while i < out.len() && out[i] != '\n' {
+                out[i] = ' ';
+                i += 1;
+            }
+            continue;
+        } else if c == '/' && out.get(i + 1) == Some(&'*') {
+            let end = (i + 2..out.len())
+                .find(|&j| out[j] == '*' && out.get(j + 1) == Some(&'/'))
+                .map_or(out.len(), |j| j + 2);
+            for c in &mut out[i..end] {
+                if *c != '\n' {
+                    *c = ' ';
+                }
+            }
+            i = end;
+            continue;
+        } else if !c.is_whitespace() {
+            if let Some(comma) = pending_comma.take() {
+                if c == '}' || c == ']' {
+                    out[comma] = ' ';
+                }
+            }
+            match c {
+                ',' => pending_comma = Some(i),
+                '"' => in_string = true,
+                _ => {}
+            }
+        }
+        i += 1;
+    }
+    out.into_iter().collect()
+}
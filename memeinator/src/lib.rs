@@ -1,10 +1,11 @@
 use std::{
+    cmp::Reverse,
     collections::HashMap,
     fs,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::{anyhow, Context, Error};
 use fontdue::{
     layout::{
         CoordinateSystem, GlyphPosition, GlyphRasterConfig, HorizontalAlign, Layout,
@@ -12,17 +13,30 @@ use fontdue::{
     },
     Font, FontSettings, Metrics,
 };
-use image::{save_buffer, DynamicImage, GrayImage, Luma, RgbImage, Rgba, RgbaImage};
-use serde::{Deserialize, Serialize};
+use image::{
+    imageops::FilterType, save_buffer, GrayImage, ImageFormat, Luma, RgbImage, Rgba, RgbaImage,
+};
+use serde::{Deserialize, Deserializer, Serialize};
 
 static FONT: &[u8] = include_bytes!("../resources/BebasNeue-Regular.ttf");
+/// Text is never shrunk below this size to make it fit, unless its box sets its own minimum
+const MIN_FONT_SIZE: f32 = 5.;
 
+mod embedded;
+mod error;
+mod filter;
 mod git_ops;
+mod jsonc;
 
-#[derive(Debug)]
+pub use error::MemeError;
+pub use filter::{mono, Filter};
+
+#[derive(Debug, Clone)]
 pub struct MemeTemplate {
     image: RgbaImage,
     config: MemeConfig,
+    resize_filter: FilterType,
+    linear_blending: bool,
 }
 
 #[derive(Debug)]
@@ -33,62 +47,323 @@ pub enum MemeContent {
 }
 
 impl MemeTemplate {
+    /// A template that isn't saved anywhere, such as one still being made in an editor.
+    /// The image is used as is, `config.panels` isn't laid out.
+    pub fn from_image(image: RgbaImage, config: MemeConfig) -> Result<Self, MemeError> {
+        validate_template(&config, &image).map_err(MemeError::InvalidUnsavedTemplate)?;
+        Ok(MemeTemplate {
+            config,
+            image,
+            resize_filter: FilterType::Lanczos3,
+            linear_blending: false,
+        })
+    }
+
+    pub fn fields(&self) -> &[MemeField] {
+        &self.config.text
+    }
+
+    /// The template's boxes, to adjust them for a single meme
+    pub fn fields_mut(&mut self) -> &mut [MemeField] {
+        &mut self.config.text
+    }
+
+    /// The size of the template image
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.image.dimensions()
+    }
+
+    /// The template image, without any text
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    /// The config the template was loaded with. Panel templates have their panels already
+    /// laid out into `text`.
+    pub fn config(&self) -> &MemeConfig {
+        &self.config
+    }
+
+    /// Whether saving the template's config and loading it back gives the same config
+    pub fn config_round_trips(&self) -> bool {
+        let saved = match serde_json::to_value(&self.config) {
+            Ok(saved) => saved,
+            Err(_) => return false,
+        };
+        serde_json::from_value::<MemeConfig>(saved.clone())
+            .and_then(|loaded| serde_json::to_value(&loaded))
+            .is_ok_and(|resaved| resaved == saved)
+    }
+
+    /// The text color the template was made for, if it sets one
+    pub fn text_color(&self) -> Option<Rgba<u8>> {
+        self.config
+            .color
+            .map(|color| Rgba(color.map(|x| (x.clamp(0., 1.) * u8::MAX as f32).round() as u8)))
+    }
+
+    /// The template's own watermark, overriding the configured one. `Some(None)` means no watermark.
+    pub fn watermark(&self) -> Option<Option<&str>> {
+        self.config.watermark.as_ref().map(|w| w.as_deref())
+    }
+
+    /// Composites the template over a background, stretched to the template's size
+    pub fn with_background(mut self, background: &RgbaImage) -> Self {
+        let (width, height) = self.image.dimensions();
+        let mut base = if background.dimensions() == (width, height) {
+            background.clone()
+        } else {
+            image::imageops::resize(background, width, height, self.resize_filter)
+        };
+        image::imageops::overlay(&mut base, &self.image, 0, 0);
+        self.image = base;
+        self
+    }
+
+    /// Sets the filter used whenever an image is resized. Defaults to Lanczos3.
+    pub fn with_resize_filter(mut self, filter: FilterType) -> Self {
+        self.resize_filter = filter;
+        self
+    }
+
+    /// Blends text in linear light rather than sRGB, for cleaner antialiased edges
+    pub fn with_linear_blending(mut self, linear_blending: bool) -> Self {
+        self.linear_blending = linear_blending;
+        self
+    }
+
+    /// Caps the number of wrapped lines in every text box
+    pub fn with_max_lines(mut self, max_lines: usize) -> Self {
+        for field in &mut self.config.text {
+            field.max_lines = Some(max_lines);
+        }
+        self
+    }
+
+    /// Composites the template over a solid color
+    pub fn with_background_color(self, color: Rgba<u8>) -> Self {
+        let (width, height) = self.image.dimensions();
+        self.with_background(&RgbaImage::from_pixel(width, height, color))
+    }
+
     pub fn render(
-        mut self,
+        self,
         text_color: Rgba<u8>,
         content: Vec<MemeContent>,
         max_font_size: f32,
         watermark_msg: Option<&str>,
         watermark_size_fraction: f32,
     ) -> RgbaImage {
-        let font = fontdue::Font::from_bytes(FONT, FontSettings::default()).unwrap();
-        let mut layout = Layout::new(CoordinateSystem::PositiveYDown);
+        self.render_with(
+            &mut RenderContext::new(),
+            text_color,
+            content,
+            max_font_size,
+            watermark_msg,
+            watermark_size_fraction,
+        )
+    }
+
+    /// Like `render_with`, but also reports where each box's text ended up, for previews
+    /// that draw over the rendered meme
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_with_report(
+        self,
+        ctx: &mut RenderContext,
+        text_color: Rgba<u8>,
+        content: Vec<MemeContent>,
+        max_font_size: f32,
+        watermark_msg: Option<&str>,
+        watermark_size_fraction: f32,
+    ) -> (RgbaImage, RenderReport) {
+        let boxes = self
+            .config
+            .text
+            .iter()
+            .zip(content.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(field, content)| match content {
+                Some(MemeContent::Text(text)) => {
+                    let area = field.text_area();
+                    let (glyphs, fit) = get_filling_glyphs(
+                        &area,
+                        self.config.fill_mode,
+                        &ctx.fonts,
+                        &mut ctx.layout,
+                        MIN_FONT_SIZE,
+                        max_font_size,
+                        text,
+                    );
+                    Some(BoxReport {
+                        fit,
+                        bounds: glyph_bounds(glyphs, &area),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        let image = self.render_with(
+            ctx,
+            text_color,
+            content,
+            max_font_size,
+            watermark_msg,
+            watermark_size_fraction,
+        );
+        (image, RenderReport { boxes })
+    }
+
+    /// Lays out the text going into each box without rendering it.
+    /// Boxes that get something other than text, or nothing at all, are `None`.
+    pub fn measure(
+        &self,
+        ctx: &mut RenderContext,
+        content: &[MemeContent],
+        max_font_size: f32,
+    ) -> Vec<Option<TextFit>> {
+        self.config
+            .text
+            .iter()
+            .zip(content.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(field, content)| match content {
+                Some(MemeContent::Text(text)) => Some(
+                    get_filling_glyphs(
+                        &field.text_area(),
+                        self.config.fill_mode,
+                        &ctx.fonts,
+                        &mut ctx.layout,
+                        MIN_FONT_SIZE,
+                        max_font_size,
+                        text,
+                    )
+                    .1,
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The coverage masks the text of each box is drawn with, sized to the box's text area.
+    /// Boxes that get something other than text, or nothing at all, are `None`.
+    pub fn text_masks(
+        &self,
+        ctx: &mut RenderContext,
+        content: &[MemeContent],
+        max_font_size: f32,
+    ) -> Vec<Option<GrayImage>> {
+        self.config
+            .text
+            .iter()
+            .zip(content.iter().map(Some).chain(std::iter::repeat(None)))
+            .map(|(field, content)| match content {
+                Some(MemeContent::Text(text)) => Some(render_text(
+                    &mut ctx.raster_cache,
+                    &mut ctx.layout,
+                    &ctx.fonts,
+                    max_font_size,
+                    &field.text_area(),
+                    self.config.fill_mode,
+                    text,
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The coverage mask the watermark is drawn with, spanning the template's width
+    pub fn watermark_mask(
+        &self,
+        ctx: &mut RenderContext,
+        watermark_msg: &str,
+        watermark_size_fraction: f32,
+    ) -> GrayImage {
+        render_watermark(
+            &mut ctx.raster_cache,
+            &mut ctx.layout,
+            &ctx.fonts,
+            &self.image,
+            watermark_size_fraction,
+            watermark_msg,
+        )
+        .0
+    }
 
-        let mut raster_cache = HashMap::new();
+    /// The indices of the text boxes whose text would still overflow at the smallest font size,
+    /// and so be clipped when rendered
+    pub fn overflowing_fields(
+        &self,
+        ctx: &mut RenderContext,
+        content: &[MemeContent],
+        max_font_size: f32,
+    ) -> Vec<usize> {
+        self.measure(ctx, content, max_font_size)
+            .iter()
+            .enumerate()
+            .filter(|(_, fit)| matches!(fit, Some(fit) if !fit.fits))
+            .map(|(i, _)| i)
+            .collect()
+    }
 
+    /// Like `render`, but reuses the font and rasterized glyphs in `ctx` across calls
+    pub fn render_with(
+        mut self,
+        ctx: &mut RenderContext,
+        text_color: Rgba<u8>,
+        content: Vec<MemeContent>,
+        max_font_size: f32,
+        watermark_msg: Option<&str>,
+        watermark_size_fraction: f32,
+    ) -> RgbaImage {
+        let default_color = text_color.0.map(|x| x as f32 / u8::MAX as f32);
         for (content, bb) in content.into_iter().zip(&self.config.text) {
+            let color = bb.color.unwrap_or(default_color);
             match content {
+                MemeContent::Text(text) if self.config.rainbow => overlay_rainbow_text(
+                    &mut self.image,
+                    ctx,
+                    max_font_size,
+                    &bb.text_area(),
+                    self.config.fill_mode,
+                    &text,
+                    color[3],
+                    self.linear_blending,
+                ),
                 MemeContent::Text(text) => {
-                    let max_height = bb.max.1 - bb.min.1;
-                    let max_width = bb.max.0 - bb.min.0;
+                    let area = bb.text_area();
                     let mask = render_text(
-                        &mut raster_cache,
-                        &mut layout,
-                        &font,
+                        &mut ctx.raster_cache,
+                        &mut ctx.layout,
+                        &ctx.fonts,
                         max_font_size,
-                        (max_width, max_height),
+                        &area,
+                        self.config.fill_mode,
                         &text,
-                        text_color,
                     );
 
                     simple_overlay(
                         &mut self.image,
                         &mask,
-                        [
-                            text_color[0] as f32,
-                            text_color[1] as f32,
-                            text_color[2] as f32,
-                            text_color[3] as f32
-                        ],
-                        bb.min,
+                        color,
+                        area.min,
+                        self.linear_blending,
                     )
                 }
                 MemeContent::Meme(meme, sub_content) => {
-                    let img = meme.render(text_color, sub_content, max_font_size, None, 0.);
-                    overlay_image_into_slot(img, &mut self.image, bb);
+                    let img =
+                        meme.render_with(ctx, text_color, sub_content, max_font_size, None, 0.);
+                    overlay_image_into_slot(img, &mut self.image, bb, self.resize_filter);
                 }
                 MemeContent::Image(img) => {
-                    overlay_image_into_slot(img, &mut self.image, bb);
+                    overlay_image_into_slot(img, &mut self.image, bb, self.resize_filter);
                 }
             }
         }
 
         if let Some(watermark) = watermark_msg {
-            let (watermark, pos) = render_watermark(
-                &mut raster_cache,
-                &mut layout,
-                &font,
-                (self.image.width(), self.image.height()),
+            let (watermark, pos, color) = render_watermark(
+                &mut ctx.raster_cache,
+                &mut ctx.layout,
+                &ctx.fonts,
+                &self.image,
                 watermark_size_fraction,
                 watermark,
             );
@@ -96,8 +371,9 @@ impl MemeTemplate {
             simple_overlay(
                 &mut self.image,
                 &watermark,
-                self.config.color.unwrap_or([0., 0., 0., 1.]),
+                color,
                 (0, pos),
+                self.linear_blending,
             )
         }
 
@@ -105,7 +381,142 @@ impl MemeTemplate {
     }
 }
 
+/// How a box's text was laid out
+#[derive(Debug, Clone, Copy)]
+pub struct TextFit {
+    pub font_size: f32,
+    pub lines: usize,
+    /// Whether the text fit in the box, rather than overflowing even at the smallest size
+    pub fits: bool,
+}
+
+/// Where a box's text was drawn
+#[derive(Debug, Clone, Copy)]
+pub struct BoxReport {
+    pub fit: TextFit,
+    /// The min and max corners of the pixels the text covers, on the template image.
+    /// `None` when the text has nothing visible, like an empty or all-space caption.
+    pub bounds: Option<((u32, u32), (u32, u32))>,
+}
+
+/// What [`MemeTemplate::render_with_report`] drew
+#[derive(Debug, Clone)]
+pub struct RenderReport {
+    /// One entry per box, `None` for boxes that got something other than text, or nothing
+    pub boxes: Vec<Option<BoxReport>>,
+}
+
+type RasterCache = HashMap<GlyphRasterConfig, (Metrics, Vec<u8>)>;
+
+/// The fonts and glyph rasters used while rendering. Keeping one around across renders
+/// saves parsing the fonts and rasterizing the same glyphs again.
+pub struct RenderContext {
+    /// The meme font, then the fallbacks for characters it doesn't have, in order
+    fonts: Vec<Font>,
+    layout: Layout,
+    raster_cache: RasterCache,
+}
+
+impl RenderContext {
+    pub fn new() -> Self {
+        Self {
+            fonts: vec![Font::from_bytes(FONT, FontSettings::default()).unwrap()],
+            layout: Layout::new(CoordinateSystem::PositiveYDown),
+            raster_cache: HashMap::new(),
+        }
+    }
+
+    /// Rasterizes the glyphs of `chars` at `px` ahead of time
+    pub fn prewarm(&mut self, chars: &str, px: f32) {
+        self.layout.reset(&LayoutSettings::default());
+        append_text(&mut self.layout, &self.fonts, chars, px);
+        for glyph in self.layout.glyphs() {
+            self.raster_cache
+                .entry(glyph.key)
+                .or_insert_with(|| self.fonts[glyph.font_index].rasterize_config(glyph.key));
+        }
+    }
+
+    /// The coverage mask `text` is drawn with in `field`, sized to the field's text area.
+    /// Lets editors preview a caption without a whole template to render.
+    pub fn text_mask(
+        &mut self,
+        field: &MemeField,
+        fill_mode: FillMode,
+        text: &str,
+        max_font_size: f32,
+    ) -> GrayImage {
+        render_text(
+            &mut self.raster_cache,
+            &mut self.layout,
+            &self.fonts,
+            max_font_size,
+            &field.text_area(),
+            fill_mode,
+            text,
+        )
+    }
+
+    /// Adds a font to draw the characters the fonts before it don't have
+    pub fn with_fallback_font(mut self, bytes: &[u8]) -> Result<Self, &'static str> {
+        self.fonts
+            .push(Font::from_bytes(bytes, FontSettings::default())?);
+        Ok(self)
+    }
+}
+
+impl Default for RenderContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Draws only the watermark onto any image, the same way memes get theirs, with the configured
+/// size, blending and fallback fonts
+pub fn apply_watermark(img: RgbaImage, config: &Config, text: &str) -> RgbaImage {
+    let mut ctx = config.render_context().unwrap_or_else(|e| {
+        log::warn!("Watermarking without the fallback fonts: {:#}", e);
+        RenderContext::new()
+    });
+    apply_watermark_with(&mut ctx, img, config, text)
+}
+
+/// Like `apply_watermark`, reusing a `RenderContext` across images
+pub fn apply_watermark_with(
+    ctx: &mut RenderContext,
+    mut img: RgbaImage,
+    config: &Config,
+    text: &str,
+) -> RgbaImage {
+    let (watermark, pos, color) = render_watermark(
+        &mut ctx.raster_cache,
+        &mut ctx.layout,
+        &ctx.fonts,
+        &img,
+        config.watermark_size_fraction(),
+        text,
+    );
+    simple_overlay(
+        &mut img,
+        &watermark,
+        color,
+        (0, pos),
+        config.linear_blending(),
+    );
+    img
+}
+
 pub fn add_top_text(img: RgbaImage, text: &str, color: Rgba<u8>) -> RgbaImage {
+    add_top_text_with(&mut RenderContext::new(), img, text, color)
+}
+
+/// Like `add_top_text`, drawing with the context's fonts, fallback fonts included
+pub fn add_top_text_with(
+    ctx: &mut RenderContext,
+    img: RgbaImage,
+    text: &str,
+    color: Rgba<u8>,
+) -> RgbaImage {
     let new_height = img.height() + img.width() / 4;
     let mut new = RgbaImage::new(img.width(), new_height);
 
@@ -116,26 +527,118 @@ pub fn add_top_text(img: RgbaImage, text: &str, color: Rgba<u8>) -> RgbaImage {
         image: img,
         config: MemeConfig {
             color: Some([1.; 4]),
-            text: vec![MemeField { min: (0, 0), max }],
+            text: vec![MemeField {
+                min: (0, 0),
+                max,
+                ..Default::default()
+            }],
+            ..Default::default()
         },
+        resize_filter: FilterType::Lanczos3,
+        linear_blending: false,
     };
 
     //tt_template.render(vec![MemeContent::Text(text.to_owned())], 50., None, 0.)
-    tt_template.render(color,vec![MemeContent::Text(text.to_owned())], 50., None, 0.)
+    tt_template.render_with(
+        ctx,
+        color,
+        vec![MemeContent::Text(text.to_owned())],
+        50.,
+        None,
+        0.,
+    )
+}
+
+/// Outlines every text box on the image, useful to check template coordinates
+pub fn draw_field_outlines(img: &mut RgbaImage, fields: &[MemeField], color: Rgba<u8>) {
+    let mut put_pixel = |x: u32, y: u32| {
+        if x < img.width() && y < img.height() {
+            img.put_pixel(x, y, color);
+        }
+    };
+    for field in fields {
+        let (left, top) = field.min;
+        let right = field.max.0.saturating_sub(1).max(left);
+        let bottom = field.max.1.saturating_sub(1).max(top);
+        for x in left..=right {
+            put_pixel(x, top);
+            put_pixel(x, bottom);
+        }
+        for y in top..=bottom {
+            put_pixel(left, y);
+            put_pixel(right, y);
+        }
+    }
+}
+
+/// Finds the `(min, max)` bounds of everything that isn't border, taking the top left pixel
+/// as the border color. Pixels whose channels all lie within `tolerance` of it count as border.
+/// Returns the whole image if it's uniform.
+pub fn content_bounds(img: &RgbaImage, tolerance: u8) -> ((u32, u32), (u32, u32)) {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return ((0, 0), (width, height));
+    }
+    let border = img.get_pixel(0, 0).0;
+    let mut min = (width, height);
+    let mut max = (0, 0);
+    for (x, y, pixel) in img.enumerate_pixels() {
+        let is_border = pixel
+            .0
+            .iter()
+            .zip(border)
+            .all(|(&a, b)| a.abs_diff(b) <= tolerance);
+        if !is_border {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x + 1), max.1.max(y + 1));
+        }
+    }
+    if min.0 >= max.0 {
+        ((0, 0), (width, height))
+    } else {
+        (min, max)
+    }
 }
 
-fn overlay_image_into_slot(img: RgbaImage, base: &mut RgbaImage, bb: &MemeField) {
+/// Pastes `img` into the box `bb`, scaled as its `image_fit` says
+fn overlay_image_into_slot(
+    img: RgbaImage,
+    base: &mut RgbaImage,
+    bb: &MemeField,
+    filter: FilterType,
+) {
     let img_base_width = img.width() as f32;
     let img_base_height = img.height() as f32;
     let max_height = (bb.max.1 - bb.min.1) as f32;
     let max_width = (bb.max.0 - bb.min.0) as f32;
-    let limited_by_y = max_width / max_height > img_base_width / img_base_height;
+    let fit = bb.image_fit.unwrap_or_default();
+    if let ImageFit::Stretch = fit {
+        let rescaled = image::imageops::resize(&img, max_width as u32, max_height as u32, filter);
+        image::imageops::overlay(base, &rescaled, bb.min.0, bb.min.1);
+        return;
+    }
+    // contain scales by the tighter side, cover by the looser one
+    let limited_by_y = (max_width / max_height > img_base_width / img_base_height)
+        == matches!(fit, ImageFit::Contain);
     let (width, height) = if limited_by_y {
         (img_base_width * (max_height / img_base_height), max_height)
     } else {
         (max_width, img_base_height * (max_width / img_base_width))
     };
-    let rescaled = image::imageops::thumbnail(&img, width as u32, height as u32);
+    let rescaled =
+        image::imageops::resize(&img, (width as u32).max(1), (height as u32).max(1), filter);
+    if let ImageFit::Cover = fit {
+        let cropped = image::imageops::crop_imm(
+            &rescaled,
+            ((width - max_width) / 2.) as u32,
+            ((height - max_height) / 2.) as u32,
+            max_width as u32,
+            max_height as u32,
+        )
+        .to_image();
+        image::imageops::overlay(base, &cropped, bb.min.0, bb.min.1);
+        return;
+    }
     let (x_offset, y_offset) = if limited_by_y {
         ((max_width - width) / 2., 0.)
     } else {
@@ -149,16 +652,52 @@ fn overlay_image_into_slot(img: RgbaImage, base: &mut RgbaImage, bb: &MemeField)
     );
 }
 
+/// Appends `text` to the layout, drawing each character with the first font that has it.
+/// Whitespace stays in the font of the text around it.
+fn append_text(layout: &mut Layout, fonts: &[Font], text: &str, px: f32) {
+    if fonts.len() == 1 {
+        layout.append(fonts, &TextStyle::new(text, px, 0));
+        return;
+    }
+    let font_for = |c: char| {
+        fonts
+            .iter()
+            .position(|font| font.lookup_glyph_index(c) != 0)
+            .unwrap_or(0)
+    };
+    let mut run_start = 0;
+    let mut run_font = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() || c.is_control() {
+            continue;
+        }
+        let font = font_for(c);
+        match run_font {
+            Some(current) if current != font => {
+                layout.append(fonts, &TextStyle::new(&text[run_start..i], px, current));
+                run_start = i;
+                run_font = Some(font);
+            }
+            Some(_) => {}
+            None => run_font = Some(font),
+        }
+    }
+    layout.append(
+        fonts,
+        &TextStyle::new(&text[run_start..], px, run_font.unwrap_or(0)),
+    );
+}
+
 fn render_glyphs(
     glyphs: &[GlyphPosition],
-    raster_cache: &mut HashMap<GlyphRasterConfig, (Metrics, Vec<u8>)>,
-    font: &Font,
+    raster_cache: &mut RasterCache,
+    fonts: &[Font],
     mut put_pixel: impl FnMut(u32, u32, u8),
 ) {
     for glyph in glyphs.iter().filter(|x| !x.char_data.is_control()) {
         let (ref metrics, ref bytes) = raster_cache
             .entry(glyph.key)
-            .or_insert_with(|| font.rasterize_config(glyph.key));
+            .or_insert_with(|| fonts[glyph.font_index].rasterize_config(glyph.key));
 
         for x in 0..metrics.width {
             for y in 0..metrics.height {
@@ -171,76 +710,132 @@ fn render_glyphs(
     }
 }
 
-// TODO: fix oversizing
+/// The corners of the pixels `render_glyphs` covers for `glyphs` laid out in `area`,
+/// clipped to the area and moved onto the whole image
+fn glyph_bounds(glyphs: &[GlyphPosition], area: &MemeField) -> Option<((u32, u32), (u32, u32))> {
+    let size = (area.max.0 - area.min.0, area.max.1 - area.min.1);
+    glyphs
+        .iter()
+        .filter(|glyph| !glyph.char_data.is_control() && glyph.width > 0 && glyph.height > 0)
+        .map(|glyph| {
+            let (x, y) = (glyph.x as u32, glyph.y as u32);
+            (
+                (x.min(size.0), y.min(size.1)),
+                (
+                    (x + glyph.width as u32).min(size.0),
+                    (y + glyph.height as u32).min(size.1),
+                ),
+            )
+        })
+        .reduce(|(min_a, max_a), (min_b, max_b)| {
+            (
+                (min_a.0.min(min_b.0), min_a.1.min(min_b.1)),
+                (max_a.0.max(max_b.0), max_a.1.max(max_b.1)),
+            )
+        })
+        .filter(|(min, max)| min.0 < max.0 && min.1 < max.1)
+        .map(|(min, max)| {
+            (
+                (min.0 + area.min.0, min.1 + area.min.1),
+                (max.0 + area.min.0, max.1 + area.min.1),
+            )
+        })
+}
+
 fn get_filling_glyphs<'a>(
-    size: (u32, u32),
-    font: &Font,
+    field: &MemeField,
+    fill_mode: FillMode,
+    fonts: &[Font],
     layout: &'a mut Layout,
     min_font_size: f32,
     max_font_size: f32,
     text: &str,
-) -> &'a [GlyphPosition] {
-    let max_width = size.0 as f32;
-    let max_height = size.1 as f32;
-    let mut min = min_font_size;
-    let mut max = max_font_size;
-
-    let abs_max_lines = text.split(char::is_whitespace).count();
-
-    loop {
-        let candidate = min + max / 2.;
-        layout.reset(&LayoutSettings {
-            max_height: Some(max_height),
-            max_width: Some(max_width),
-            horizontal_align: HorizontalAlign::Center,
-            vertical_align: VerticalAlign::Top,
-            wrap_style: WrapStyle::Word,
-            wrap_hard_breaks: true,
-            ..Default::default()
-        });
-        layout.append(
-            &[font],
-            &TextStyle {
-                text,
-                px: candidate,
-                font_index: 0,
-                user_data: (),
-            },
-        );
-        if layout.lines() > abs_max_lines || layout.height() > max_height {
-            max = candidate;
-        } else if min - max <= 0.25 {
-            break layout.glyphs();
-        } else {
-            min = candidate;
-        }
+) -> (&'a [GlyphPosition], TextFit) {
+    let single_line = field.single_line;
+    let max_width = (field.max.0 - field.min.0) as f32;
+    let max_height = (field.max.1 - field.min.1) as f32;
+    let mut min = field.min_font_size.unwrap_or(min_font_size);
+    let mut max = field.max_font_size.unwrap_or(max_font_size).max(min);
+
+    let abs_max_lines = if single_line {
+        1
+    } else {
+        let word_count = text.split(char::is_whitespace).count();
+        field
+            .max_lines
+            .map_or(word_count, |max_lines| word_count.min(max_lines.max(1)))
+    };
+
+    let settings = LayoutSettings {
+        max_height: Some(max_height),
+        max_width: Some(max_width),
+        horizontal_align: HorizontalAlign::Center,
+        vertical_align: VerticalAlign::Top,
+        wrap_style: WrapStyle::Word,
+        wrap_hard_breaks: !single_line,
+        ..Default::default()
+    };
+    let mut fits = |px: f32| {
+        layout.reset(&settings);
+        append_text(layout, fonts, text, px);
+        // words too long to wrap spill past the right edge
+        let width = layout
+            .glyphs()
+            .iter()
+            .map(|g| g.x + g.width as f32)
+            .fold(0., f32::max);
+        layout.lines() <= abs_max_lines && layout.height() <= max_height && width <= max_width
+    };
+
+    // a fixed size that would overflow shrinks like `Fit` does, down from that size
+    if let FillMode::HeightFraction(fraction) = fill_mode {
+        max = max_height * fraction;
+        min = min.min(max);
     }
+    let (font_size, fits) = if fits(max) {
+        (max, true)
+    } else {
+        while max - min > 0.25 {
+            let candidate = (min + max) / 2.;
+            if fits(candidate) {
+                min = candidate;
+            } else {
+                max = candidate;
+            }
+        }
+        (min, fits(min))
+    };
+    let lines = layout.lines();
+    (
+        layout.glyphs(),
+        TextFit {
+            font_size,
+            lines,
+            fits,
+        },
+    )
 }
 
 fn render_watermark(
-    raster_cache: &mut HashMap<GlyphRasterConfig, (Metrics, Vec<u8>)>,
+    raster_cache: &mut RasterCache,
     layout: &mut Layout,
-    font: &Font,
-    image_size: (u32, u32),
+    fonts: &[Font],
+    image: &RgbaImage,
     watermark_size_fraction: f32,
     watermark: &str,
-) -> (GrayImage, u32) {
-    let (img_width, img_height) = image_size;
+) -> (GrayImage, u32, [f32; 4]) {
+    let (img_width, img_height) = image.dimensions();
     let font_size = img_width.min(img_height) as f32 / watermark_size_fraction;
     layout.reset(&LayoutSettings {
+        max_width: Some(img_width as f32),
         horizontal_align: HorizontalAlign::Left,
         vertical_align: VerticalAlign::Middle,
         ..Default::default()
     });
-    layout.append(
-        &[font],
-        &TextStyle {
-            text: watermark,
-            px: font_size,
-            font_index: 0,
-            user_data: (),
-        },
-    );
+    append_text(layout, fonts, watermark, font_size);
+    let line_height = fonts[0]
+        .horizontal_line_metrics(font_size)
+        .map_or(font_size, |metrics| metrics.new_line_size);
 
     let mut gray_image = GrayImage::from_vec(
         img_width,
@@ -249,35 +844,135 @@ fn render_watermark(
     )
     .unwrap();
 
-    render_glyphs(layout.glyphs(), raster_cache, &font, |x, y, coverage| {
+    render_glyphs(layout.glyphs(), raster_cache, fonts, |x, y, coverage| {
         gray_image.put_pixel(x, y, Luma([coverage]));
     });
 
-    (gray_image, img_height - font_size.ceil() as u32)
+    // the last line sits where a lone line would, the ones it wrapped from go above it
+    let wrapped_height = (layout.height() - line_height).max(0.).round() as u32;
+    let pos = img_height
+        .saturating_sub(font_size.ceil() as u32)
+        .saturating_sub(wrapped_height);
+    let color = contrasting_color(image, &gray_image, pos);
+    (gray_image, pos, color)
+}
+
+/// Picks black or white, whichever stands out more against the pixels under the mask
+fn contrasting_color(image: &RgbaImage, mask: &GrayImage, y_offset: u32) -> [f32; 4] {
+    let mut luminance_sum = 0.;
+    let mut coverage_sum = 0.;
+    for (x, y, coverage) in mask.enumerate_pixels() {
+        let y = y + y_offset;
+        if coverage.0[0] == 0 || x >= image.width() || y >= image.height() {
+            continue;
+        }
+        let [r, g, b, _] = image.get_pixel(x, y).0.map(|x| x as f32 / u8::MAX as f32);
+        let coverage = coverage.0[0] as f32 / u8::MAX as f32;
+        luminance_sum += (0.2126 * r + 0.7152 * g + 0.0722 * b) * coverage;
+        coverage_sum += coverage;
+    }
+
+    if coverage_sum > 0. && luminance_sum / coverage_sum < 0.5 {
+        [1., 1., 1., 1.]
+    } else {
+        [0., 0., 0., 1.]
+    }
 }
 
 fn render_text(
-    raster_cache: &mut HashMap<GlyphRasterConfig, (Metrics, Vec<u8>)>,
+    raster_cache: &mut RasterCache,
     layout: &mut Layout,
-    font: &Font,
+    fonts: &[Font],
     max_font_size: f32,
-    size: (u32, u32),
+    field: &MemeField,
+    fill_mode: FillMode,
     text: &str,
-    color: Rgba<u8>
 ) -> GrayImage {
+    let size = (field.max.0 - field.min.0, field.max.1 - field.min.1);
     let mut gray_image =
         GrayImage::from_vec(size.0, size.1, vec![0; (size.0 * size.1) as usize]).unwrap();
 
-    let glyphs = get_filling_glyphs(size, &font, layout, 5., max_font_size, text);
+    let (glyphs, _) = get_filling_glyphs(
+        field,
+        fill_mode,
+        fonts,
+        layout,
+        MIN_FONT_SIZE,
+        max_font_size,
+        text,
+    );
 
-    render_glyphs(glyphs, raster_cache, &font, |x, y, coverage| {
-        gray_image.put_pixel(x, y, Luma([coverage]));
+    // text that doesn't fit even at the smallest size spills past the box, clip it
+    render_glyphs(glyphs, raster_cache, fonts, |x, y, coverage| {
+        if x < size.0 && y < size.1 {
+            gray_image.put_pixel(x, y, Luma([coverage]));
+        }
     });
 
     gray_image
 }
 
-fn simple_overlay(image: &mut RgbaImage, mask: &GrayImage, color: [f32; 4], pos: (u32, u32)) {
+/// Like `render_text` followed by `simple_overlay`, but gives every glyph its own color,
+/// going once around the hue circle over the whole text
+#[allow(clippy::too_many_arguments)]
+fn overlay_rainbow_text(
+    image: &mut RgbaImage,
+    ctx: &mut RenderContext,
+    max_font_size: f32,
+    field: &MemeField,
+    fill_mode: FillMode,
+    text: &str,
+    alpha: f32,
+    linear: bool,
+) {
+    let (glyphs, _) = get_filling_glyphs(
+        field,
+        fill_mode,
+        &ctx.fonts,
+        &mut ctx.layout,
+        MIN_FONT_SIZE,
+        max_font_size,
+        text,
+    );
+    let visible: Vec<_> = glyphs
+        .iter()
+        .filter(|glyph| !glyph.char_data.is_control() && !glyph.char_data.is_whitespace())
+        .collect();
+
+    for (i, glyph) in visible.iter().enumerate() {
+        let (metrics, bytes) = ctx
+            .raster_cache
+            .entry(glyph.key)
+            .or_insert_with(|| ctx.fonts[glyph.font_index].rasterize_config(glyph.key));
+        let mask = GrayImage::from_raw(metrics.width as u32, metrics.height as u32, bytes.clone())
+            .unwrap();
+        let [r, g, b] = hue_to_rgb(i as f32 / visible.len() as f32);
+        let pos = (field.min.0 + glyph.x as u32, field.min.1 + glyph.y as u32);
+        simple_overlay(image, &mask, [r, g, b, alpha], pos, linear);
+    }
+}
+
+/// The fully saturated, fully bright color at `hue`, given as a fraction of the circle
+fn hue_to_rgb(hue: f32) -> [f32; 3] {
+    let h = hue.rem_euclid(1.) * 6.;
+    let x = 1. - (h % 2. - 1.).abs();
+    match h as u32 {
+        0 => [1., x, 0.],
+        1 => [x, 1., 0.],
+        2 => [0., 1., x],
+        3 => [0., x, 1.],
+        4 => [x, 0., 1.],
+        _ => [1., 0., x],
+    }
+}
+
+fn simple_overlay(
+    image: &mut RgbaImage,
+    mask: &GrayImage,
+    color: [f32; 4],
+    pos: (u32, u32),
+    linear: bool,
+) {
     for x in 0..mask.width() {
         for y in 0..mask.height() {
             let mask = mask.get_pixel(x, y).0[0] as f32 / u8::MAX as f32;
@@ -285,72 +980,676 @@ fn simple_overlay(image: &mut RgbaImage, mask: &GrayImage, color: [f32; 4], pos:
             let y = pos.1 + y;
 
             if (0..image.width()).contains(&x) && (0..image.height()).contains(&y) {
-                let prev = image.get_pixel(x, y);
-                let [r, g, b, a] = prev.0.map(|x| x as f32 / u8::MAX as f32);
+                let prev = image.get_pixel_mut(x, y);
+                *prev = blend_pixel(*prev, color, mask, linear);
+            }
+        }
+    }
+}
 
-                let zipped = [(r, color[0]), (g, color[1]), (b, color[2]), (a, color[3])];
+/// Like `simple_overlay`, but places the mask at a fractional position by sampling it bilinearly
+pub fn subpixel_overlay(
+    image: &mut RgbaImage,
+    mask: &GrayImage,
+    color: [f32; 4],
+    pos: (f32, f32),
+    linear: bool,
+) {
+    let x_start = pos.0.floor().max(0.) as u32;
+    let y_start = pos.1.floor().max(0.) as u32;
+    let x_end = ((pos.0 + mask.width() as f32).ceil().max(0.) as u32).min(image.width());
+    let y_end = ((pos.1 + mask.height() as f32).ceil().max(0.) as u32).min(image.height());
 
-                let new = zipped
-                    .map(|(a, b)| (1. - mask) * a + mask * b)
-                    .map(|x| x * u8::MAX as f32)
-                    .map(|x| x as u8);
-                image.put_pixel(x, y, Rgba(new));
+    for x in x_start..x_end {
+        for y in y_start..y_end {
+            let coverage = sample_bilinear(mask, x as f32 - pos.0, y as f32 - pos.1);
+            if coverage > 0. {
+                let prev = image.get_pixel_mut(x, y);
+                *prev = blend_pixel(*prev, color, coverage, linear);
             }
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+fn sample_bilinear(mask: &GrayImage, x: f32, y: f32) -> f32 {
+    let get = |x: f32, y: f32| {
+        if x >= 0. && y >= 0. && x < mask.width() as f32 && y < mask.height() as f32 {
+            mask.get_pixel(x as u32, y as u32).0[0] as f32 / u8::MAX as f32
+        } else {
+            0.
+        }
+    };
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let top = get(x0, y0) * (1. - fx) + get(x0 + 1., y0) * fx;
+    let bottom = get(x0, y0 + 1.) * (1. - fx) + get(x0 + 1., y0 + 1.) * fx;
+    top * (1. - fy) + bottom * fy
+}
+
+/// Source-over compositing of `color` onto `prev`, with the mask coverage scaling the color's alpha.
+/// With `linear`, colors are mixed in linear light, which keeps antialiased edges from darkening.
+fn blend_pixel(prev: Rgba<u8>, mut color: [f32; 4], coverage: f32, linear: bool) -> Rgba<u8> {
+    let [mut r, mut g, mut b, a] = prev.0.map(|x| x as f32 / u8::MAX as f32);
+    if linear {
+        [r, g, b] = [r, g, b].map(srgb_to_linear);
+        for c in &mut color[..3] {
+            *c = srgb_to_linear(*c);
+        }
+    }
+
+    let src_a = coverage * color[3];
+    let out_a = src_a + a * (1. - src_a);
+    let blend = |dst: f32, src: f32| {
+        if out_a > 0. {
+            (src * src_a + dst * a * (1. - src_a)) / out_a
+        } else {
+            0.
+        }
+    };
+
+    let mut new = [
+        blend(r, color[0]),
+        blend(g, color[1]),
+        blend(b, color[2]),
+        out_a,
+    ];
+    if linear {
+        for c in &mut new[..3] {
+            *c = linear_to_srgb(*c);
+        }
+    }
+    Rgba(new.map(|x| (x * u8::MAX as f32).round() as u8))
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct MemeConfig {
     pub color: Option<[f32; 4]>,
+    /// The template's boxes. Panel templates can leave this out and give each panel its own.
+    #[serde(default)]
+    pub text: Vec<MemeField>,
+    #[serde(default)]
+    pub fill_mode: FillMode,
+    /// Color each glyph along the hue circle instead of using the text color
+    #[serde(default)]
+    pub rainbow: bool,
+    /// Overrides the configured watermark, `null` disables it
+    #[serde(
+        default,
+        deserialize_with = "deserialize_some",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub watermark: Option<Option<String>>,
+    /// Builds the template from several images laid out on a grid, instead of a single image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panels: Option<PanelLayout>,
+}
+
+/// Lets an explicit `null` deserialize to `Some(None)` rather than `None`
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// How the font size for a text box is chosen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum FillMode {
+    /// The largest size that fits the box, up to the maximum font size
+    #[default]
+    Fit,
+    /// A fixed fraction of the box height, wrapping within the box width.
+    /// Text that wouldn't fit at that size is shrunk until it does.
+    HeightFraction(f32),
+}
+
+/// How an image or nested meme is scaled into a box
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum ImageFit {
+    /// Keep the aspect ratio and fit inside the box, centered on the side with room to spare
+    #[default]
+    Contain,
+    /// Keep the aspect ratio and fill the whole box, cropping the overflow evenly from both sides
+    Cover,
+    /// Fill the box exactly, ignoring the aspect ratio
+    Stretch,
+}
+
+/// The grid a panel template's images are laid out on, left to right and then top to bottom.
+/// Every cell is the size of the largest panel, and smaller panels are centered in theirs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PanelLayout {
+    /// How many panels go in each row
+    pub columns: u32,
+    /// The space left between panels, in pixels
+    #[serde(default)]
+    pub gap: u32,
+    pub panels: Vec<Panel>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Panel {
+    /// The panel's image, in the template's directory
+    pub image: String,
+    /// The panel's boxes, positioned on the panel's own image
+    #[serde(default)]
     pub text: Vec<MemeField>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl PanelLayout {
+    /// Where each panel goes given the panels' sizes, and the size of the whole image
+    fn arrange(&self, sizes: &[(u32, u32)]) -> (Vec<(u32, u32)>, (u32, u32)) {
+        let columns = self.columns.max(1) as usize;
+        let cell_width = sizes.iter().map(|size| size.0).max().unwrap_or(0);
+        let cell_height = sizes.iter().map(|size| size.1).max().unwrap_or(0);
+        let positions = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &(width, height))| {
+                let (column, row) = ((i % columns) as u32, (i / columns) as u32);
+                (
+                    column * (cell_width + self.gap) + (cell_width - width) / 2,
+                    row * (cell_height + self.gap) + (cell_height - height) / 2,
+                )
+            })
+            .collect();
+        let span = |cells: usize, cell: u32| cells as u32 * (cell + self.gap) - self.gap;
+        let size = if sizes.is_empty() {
+            (0, 0)
+        } else {
+            (
+                span(columns.min(sizes.len()), cell_width),
+                span(sizes.len().div_ceil(columns), cell_height),
+            )
+        };
+        (positions, size)
+    }
+
+    /// Every panel's boxes, moved along with their panel to the image `arrange` laid out
+    fn fields(&self, positions: &[(u32, u32)]) -> impl Iterator<Item = MemeField> + '_ {
+        self.panels
+            .iter()
+            .zip(positions.to_vec())
+            .flat_map(|(panel, (x, y))| {
+                panel.text.iter().map(move |field| MemeField {
+                    min: (field.min.0 + x, field.min.1 + y),
+                    max: (field.max.0 + x, field.max.1 + y),
+                    ..field.clone()
+                })
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct MemeField {
     pub min: (u32, u32),
     pub max: (u32, u32),
+    /// Keep the text on one line, shrinking it instead of wrapping
+    #[serde(default)]
+    pub single_line: bool,
+    /// The most lines the text may wrap into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_lines: Option<usize>,
+    /// The smallest size text is shrunk to, overriding the default of 5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_font_size: Option<f32>,
+    /// The largest size text is grown to, overriding the one given when rendering
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_font_size: Option<f32>,
+    /// Lets text be given to this box by name instead of by position
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Insets the text area from the box's left, top, right and bottom edges.
+    /// Negative values let text grow past the box instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub padding: Option<(i32, i32, i32, i32)>,
+    /// How an image or nested meme given to this box is scaled, `Contain` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_fit: Option<ImageFit>,
+    /// The box's text color, overriding the template's and the one given when rendering
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<[f32; 4]>,
+}
+
+impl MemeField {
+    /// The box with its padding applied, which is where text actually goes
+    fn text_area(&self) -> MemeField {
+        let (left, top, right, bottom) = self.padding.unwrap_or_default();
+        let inset = |x: u32, by: i32| (x as i64 + by as i64).clamp(0, u32::MAX as i64) as u32;
+        let min = (inset(self.min.0, left), inset(self.min.1, top));
+        // keep at least a pixel to draw into, however large the padding
+        let max = (
+            inset(self.max.0, -right).max(min.0 + 1),
+            inset(self.max.1, -bottom).max(min.1 + 1),
+        );
+        MemeField {
+            min,
+            max,
+            padding: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// A template found in one of the sources
+#[derive(Debug, Clone)]
+pub struct TemplateInfo {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether the template is an `animated.gif` rather than an `image.png`
+    pub animated: bool,
+}
+
+/// Where templates live and how to find them, apart from the render settings in `Config`
+pub struct TemplateRegistry {
+    sources: Vec<MemeSource>,
+    git_timeout: Duration,
+}
+
+impl TemplateRegistry {
+    /// A registry searching `sources` in order
+    pub fn new(sources: Vec<MemeSource>) -> Self {
+        Self {
+            sources,
+            git_timeout: Duration::from_secs(300),
+        }
+    }
+
+    /// Sets how long git gets to fetch a template for a sparse source
+    pub fn with_git_timeout(mut self, timeout: Duration) -> Self {
+        self.git_timeout = timeout;
+        self
+    }
+
+    pub fn git_timeout(&self) -> Duration {
+        self.git_timeout
+    }
+
+    /// A registry of the templates in a single directory
+    pub fn from_dir(path: impl AsRef<Path>) -> Self {
+        Self::new(vec![MemeSource::LocalPath(
+            path.as_ref().to_string_lossy().into_owned(),
+        )])
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = &MemeSource> + '_ {
+        self.sources.iter()
+    }
+
+    /// Every template in the sources, in the order they're searched
+    pub fn list(&self) -> impl Iterator<Item = TemplateInfo> + '_ {
+        self.sources.iter().flat_map(|source| match source {
+            MemeSource::Embedded => embedded::template_infos(),
+            MemeSource::GitUrl { sparse: true, .. } => source
+                .to_path()
+                .ok()
+                .flatten()
+                .and_then(|path| Some((git_ops::list_dirs(&path).ok()?, path)))
+                .map(|(names, path)| {
+                    names
+                        .into_iter()
+                        .map(|name| {
+                            let path = path.join(&name);
+                            TemplateInfo {
+                                animated: path.join("animated.gif").is_file(),
+                                name,
+                                path,
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            source => source
+                .to_path()
+                .ok()
+                .flatten()
+                .and_then(|path| path.read_dir().ok())
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter(|meme_dir| meme_dir.path().is_dir())
+                .flat_map(|meme_dir| {
+                    let path = meme_dir.path();
+                    meme_dir.file_name().into_string().map(|name| TemplateInfo {
+                        animated: path.join("animated.gif").is_file(),
+                        name,
+                        path,
+                    })
+                })
+                .filter(|info| info.name != ".git")
+                .collect(),
+        })
+    }
+
+    /// Finds a template by name, from the first source that has it
+    pub fn get(&self, template: &str) -> Result<MemeTemplate, MemeError> {
+        Self::load(self.locate_in(&self.sources, template)?, template)
+    }
+
+    /// Like `get`, but only looks in the source called `source`, see [`MemeSource::name`]
+    pub fn get_from(&self, source: &str, template: &str) -> Result<MemeTemplate, MemeError> {
+        let found = self
+            .sources
+            .iter()
+            .find(|s| s.name() == source)
+            .ok_or_else(|| MemeError::SourceNotFound(source.to_owned()))?;
+        let location = self.locate_in([found], template).map_err(|e| match e {
+            MemeError::TemplateNotFound(template) => {
+                MemeError::TemplateNotInSource(template, source.to_owned())
+            }
+            e => e,
+        })?;
+        Self::load(location, template)
+    }
+
+    /// The source `get` finds `template` in, or `get_from` when `source` is given, and the
+    /// template's directory there. The directory is `None` for an embedded template.
+    pub fn locate(
+        &self,
+        source: Option<&str>,
+        template: &str,
+    ) -> Result<(&MemeSource, Option<PathBuf>), MemeError> {
+        let sources: Vec<&MemeSource> = match source {
+            Some(name) => vec![self
+                .sources
+                .iter()
+                .find(|s| s.name() == name)
+                .ok_or_else(|| MemeError::SourceNotFound(name.to_owned()))?],
+            None => self.sources.iter().collect(),
+        };
+        for found in sources {
+            match self.locate_in([found], template) {
+                Ok(TemplateLocation::Dir(dir)) => return Ok((found, Some(dir))),
+                Ok(TemplateLocation::Embedded) => return Ok((found, None)),
+                Err(MemeError::TemplateNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(match source {
+            Some(source) => MemeError::TemplateNotInSource(template.to_owned(), source.to_owned()),
+            None => MemeError::TemplateNotFound(template.to_owned()),
+        })
+    }
+
+    /// The boxes of the template found by `get`, read from its `config.json` alone.
+    /// Skips decoding the image, which is most of the work of loading a template.
+    pub fn fields(&self, template: &str) -> Result<Vec<MemeField>, MemeError> {
+        let location = self.locate_in(&self.sources, template)?;
+        let mut config = match &location {
+            TemplateLocation::Dir(dir_path) => {
+                let config_path = dir_path.join("config.json");
+                let config = fs::read(&config_path).map_err(MemeError::source_io(&config_path))?;
+                parse_template_config(dir_path, &config)?
+            }
+            TemplateLocation::Embedded => parse_template_config(
+                &embedded::template_path(template),
+                embedded::get_config(template)
+                    .ok_or_else(|| MemeError::TemplateNotFound(template.to_owned()))?,
+            )?,
+        };
+        if let Some(layout) = &config.panels {
+            // a panel's position only depends on the sizes in the images' headers
+            let sizes = layout
+                .panels
+                .iter()
+                .map(|panel| {
+                    let image = location.read_file(template, &panel.image)?;
+                    Ok(image::io::Reader::new(std::io::Cursor::new(image))
+                        .with_guessed_format()?
+                        .into_dimensions()?)
+                })
+                .collect::<Result<Vec<_>, MemeError>>()?;
+            let (positions, _) = layout.arrange(&sizes);
+            config.text.extend(layout.fields(&positions));
+        }
+        Ok(config.text)
+    }
+
+    fn load(location: TemplateLocation, template: &str) -> Result<MemeTemplate, MemeError> {
+        match location {
+            TemplateLocation::Dir(dir_path) => load_template_from_dir(&dir_path),
+            TemplateLocation::Embedded => embedded::get_template(template)
+                .unwrap_or_else(|| Err(MemeError::TemplateNotFound(template.to_owned()))),
+        }
+    }
+
+    /// Finds where the first of `sources` to have `template` keeps it
+    fn locate_in<'a>(
+        &self,
+        sources: impl IntoIterator<Item = &'a MemeSource>,
+        template: &str,
+    ) -> Result<TemplateLocation, MemeError> {
+        for source in sources {
+            log::debug!(
+                "Looking for template {} in source {}",
+                template,
+                source.name()
+            );
+            let source_dir = match source.to_path()? {
+                Some(source_dir) => source_dir,
+                None if embedded::get_config(template).is_some() => {
+                    return Ok(TemplateLocation::Embedded)
+                }
+                None => continue,
+            };
+            if !source_dir.is_dir() {
+                log::debug!("Source {} hasn't been fetched yet", source.name());
+                continue;
+            }
+
+            // A single stat finds exact matches without listing the whole source
+            let mut components = Path::new(template).components();
+            if let (Some(Component::Normal(_)), None) = (components.next(), components.next()) {
+                let meme_dir = source_dir.join(template);
+                if let MemeSource::GitUrl { sparse: true, .. } = source {
+                    if !meme_dir.is_dir()
+                        && git_ops::list_dirs(&source_dir)
+                            .is_ok_and(|dirs| dirs.iter().any(|dir| dir == template))
+                    {
+                        git_ops::checkout_dir(&source_dir, template, self.git_timeout)?;
+                    }
+                }
+                if meme_dir.is_dir() {
+                    log::debug!("Found template {} at {}", template, meme_dir.display());
+                    return Ok(TemplateLocation::Dir(meme_dir));
+                }
+            }
+
+            for meme_dir in source_dir
+                .read_dir()
+                .map_err(MemeError::source_io(&source_dir))?
+            {
+                let meme_dir = meme_dir.map_err(MemeError::source_io(&source_dir))?;
+                if !meme_dir
+                    .metadata()
+                    .map_err(MemeError::source_io(meme_dir.path()))?
+                    .is_dir()
+                {
+                    continue;
+                }
+                let template_name = meme_dir
+                    .file_name()
+                    .into_string()
+                    .map_err(|_| MemeError::InvalidTemplateName)?;
+
+                if template_name == template {
+                    log::debug!(
+                        "Found template {} at {}",
+                        template,
+                        meme_dir.path().display()
+                    );
+                    return Ok(TemplateLocation::Dir(meme_dir.path()));
+                }
+            }
+        }
+
+        Err(MemeError::TemplateNotFound(template.to_owned()))
+    }
+}
+
+/// Where a source keeps a template
+enum TemplateLocation {
+    Dir(PathBuf),
+    Embedded,
+}
+
+impl TemplateLocation {
+    /// Reads `file` from the directory of the template called `template`
+    fn read_file(&self, template: &str, file: &str) -> Result<Vec<u8>, MemeError> {
+        match self {
+            TemplateLocation::Dir(dir_path) => read_template_file(dir_path, file),
+            TemplateLocation::Embedded => embedded::read_file(template, file),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct FileConfig {
-    sources: Option<Vec<MemeSource>>,
+    sources: Option<Vec<SourceEntry>>,
     watermark: Option<String>,
     watermark_size_fraction: Option<f32>,
+    default_output_format: Option<String>,
+    linear_blending: Option<bool>,
+    git_timeout_secs: Option<u64>,
+    default_output: Option<String>,
+    fallback_fonts: Option<Vec<PathBuf>>,
 }
 
 pub struct Config {
-    sources: Vec<MemeSource>,
+    registry: TemplateRegistry,
     watermark: String,
     watermark_size_fraction: f32,
+    default_output_format: Option<String>,
+    linear_blending: bool,
+    default_output: DefaultOutput,
+    fallback_fonts: Vec<PathBuf>,
+}
+
+/// Where memes go when no output is given: `clipboard`, `stdout`, or a directory path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DefaultOutput {
+    Clipboard,
+    Stdout,
+    Dir(PathBuf),
+}
+
+impl From<String> for DefaultOutput {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "clipboard" => DefaultOutput::Clipboard,
+            "stdout" => DefaultOutput::Stdout,
+            _ => DefaultOutput::Dir(s.into()),
+        }
+    }
+}
+
+/// A source as written in the config file: either just the source, or the source
+/// flattened together with a `priority`
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SourceEntry {
+    Plain(MemeSource),
+    Prioritized {
+        #[serde(flatten)]
+        source: MemeSource,
+        priority: i32,
+    },
 }
 
 impl From<FileConfig> for Config {
     fn from(fc: FileConfig) -> Self {
-        Self {
-            sources: fc.sources.unwrap_or_else(|| {
-                vec![MemeSource::GitUrl {
+        let sources = fc.sources.map(sort_by_priority).unwrap_or_else(|| {
+            vec![
+                #[cfg(feature = "embedded")]
+                MemeSource::Embedded,
+                MemeSource::GitUrl {
                     url: "https://github.com/TheRawMeatball/memeinator-memesrc.git".to_owned(),
                     alias: "default".to_owned(),
-                }]
-            }),
+                    sparse: false,
+                },
+            ]
+        });
+        Self {
+            registry: TemplateRegistry::new(sources)
+                .with_git_timeout(Duration::from_secs(fc.git_timeout_secs.unwrap_or(300))),
             watermark: fc
                 .watermark
                 .unwrap_or_else(|| "Made with meme-cli".to_owned()),
             watermark_size_fraction: fc.watermark_size_fraction.unwrap_or(30.),
+            default_output_format: fc.default_output_format,
+            linear_blending: fc.linear_blending.unwrap_or(false),
+            default_output: fc
+                .default_output
+                .map_or(DefaultOutput::Clipboard, DefaultOutput::from),
+            fallback_fonts: fc.fallback_fonts.unwrap_or_default(),
         }
     }
 }
 
+/// Orders sources from the highest priority to the lowest, keeping the listed order for ties.
+/// Sources without a priority have priority 0.
+fn sort_by_priority(mut entries: Vec<SourceEntry>) -> Vec<MemeSource> {
+    entries.sort_by_key(|entry| {
+        Reverse(match entry {
+            SourceEntry::Plain(_) => 0,
+            SourceEntry::Prioritized { priority, .. } => *priority,
+        })
+    });
+    entries
+        .into_iter()
+        .map(|entry| match entry {
+            SourceEntry::Plain(source) | SourceEntry::Prioritized { source, .. } => source,
+        })
+        .collect()
+}
+
 impl Config {
-    pub fn load() -> Result<Config, Error> {
-        let config_path = dirs::config_dir()
-            .ok_or_else(|| anyhow!("config dir not found"))?
-            .join("memecli.conf.json");
-        match fs::read_to_string(config_path) {
-            Ok(config_str) => Ok(serde_json::from_str::<FileConfig>(&config_str)
-                .context("The configuration file is broken")?
-                .into()),
+    /// The config file in use: `memecli.conf.toml` if it exists, `memecli.conf.json` otherwise.
+    /// They're looked for in `$MEMECLI_CONFIG_DIR` if it's set, the user's config dir otherwise.
+    pub fn path() -> Result<PathBuf, MemeError> {
+        let config_dir = match std::env::var_os("MEMECLI_CONFIG_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => dirs::config_dir().ok_or(MemeError::DirNotFound("config"))?,
+        };
+        let toml_path = config_dir.join("memecli.conf.toml");
+        if toml_path.is_file() {
+            Ok(toml_path)
+        } else {
+            Ok(config_dir.join("memecli.conf.json"))
+        }
+    }
+
+    pub fn load() -> Result<Config, MemeError> {
+        let config_path = Self::path()?;
+        log::debug!("Reading config from {}", config_path.display());
+        match fs::read_to_string(&config_path) {
+            Ok(config_str) if config_path.extension() == Some("toml".as_ref()) => {
+                Ok(toml::from_str::<FileConfig>(&config_str)
+                    .map_err(MemeError::TomlConfigParse)?
+                    .into())
+            }
+            Ok(config_str) => Ok(
+                serde_json::from_str::<FileConfig>(&jsonc::strip(&config_str))
+                    .map_err(MemeError::ConfigParse)?
+                    .into(),
+            ),
             Err(_) => Ok(FileConfig::default().into()),
         }
     }
@@ -359,57 +1658,45 @@ impl Config {
         &self.watermark
     }
 
+    /// Where this config's templates are found
+    pub fn registry(&self) -> &TemplateRegistry {
+        &self.registry
+    }
+
     pub fn fetch_source_list(&self) -> impl Iterator<Item = &MemeSource> + '_ {
-        self.sources.iter()
+        self.registry.sources()
     }
     pub fn fetch_template_list(&self) -> impl Iterator<Item = String> + '_ {
-        self.fetch_source_list()
-            .flat_map(MemeSource::to_path)
-            .flat_map(|path| path.read_dir())
-            .flatten()
-            .flatten()
-            .flat_map(|meme_dir| meme_dir.file_name().into_string())
-            .filter(|n| n != ".git")
-    }
-
-    pub fn get_meme_template(&self, template: &str) -> Result<MemeTemplate, Error> {
-        for source in &self.sources {
-            let source_dir = source.to_path()?;
-
-            for meme_dir in source_dir.read_dir().with_context(|| {
-                format!(
-                    "Cannot open meme source folder {}",
-                    source_dir.to_str().unwrap()
-                )
-            })? {
-                let meme_dir = meme_dir?;
-                if !meme_dir.metadata()?.is_dir() {
-                    continue;
-                }
-                let template_name = meme_dir
-                    .file_name()
-                    .into_string()
-                    .map_err(|_| anyhow!("can't parse template name as utf8"))?;
-
-                if template_name == template {
-                    let dir_path = meme_dir.path();
-                    let config_path = dir_path.join("config.json");
-
-                    let config = serde_json::from_str(&fs::read_to_string(config_path)?)?;
+        self.fetch_template_info_list().map(|info| info.name)
+    }
 
-                    let img = fs::File::open(dir_path.join("image.png")).with_context(|| {
-                        format!("Cannot read image.png for format {}", &template_name)
-                    })?;
+    pub fn fetch_template_info_list(&self) -> impl Iterator<Item = TemplateInfo> + '_ {
+        self.registry.list()
+    }
 
-                    let img = image::png::PngDecoder::new(img)?;
-                    let image = DynamicImage::from_decoder(img)?.to_rgba8();
+    /// Finds a template in the sources, set up with this config's blending
+    pub fn get_meme_template(&self, template: &str) -> Result<MemeTemplate, MemeError> {
+        Ok(self
+            .registry
+            .get(template)?
+            .with_linear_blending(self.linear_blending))
+    }
 
-                    return Ok(MemeTemplate { config, image });
-                }
-            }
-        }
+    /// The boxes of a template, without loading its image
+    pub fn template_fields(&self, template: &str) -> Result<Vec<MemeField>, MemeError> {
+        self.registry.fields(template)
+    }
 
-        Err(anyhow!("Can't find template {}", template))
+    /// Like `get_meme_template`, but only looks in the source called `source`
+    pub fn get_meme_template_from(
+        &self,
+        source: &str,
+        template: &str,
+    ) -> Result<MemeTemplate, MemeError> {
+        Ok(self
+            .registry
+            .get_from(source, template)?
+            .with_linear_blending(self.linear_blending))
     }
 
     /// Writes a template to the first local meme source
@@ -420,18 +1707,46 @@ impl Config {
         height: u32,
         config: MemeConfig,
         name: &str,
-    ) -> Result<(), Error> {
-        let source_path = self
-            .sources
-            .iter()
-            .find_map(|source| match source {
-                MemeSource::GitUrl { .. } => None,
-                MemeSource::LocalPath(path) => Some(Path::new(path)),
-            })
-            .ok_or(anyhow!("No local sources configured"))?;
+    ) -> Result<(), MemeError> {
+        self.write_template_in(None, false, buf, width, height, config, name)
+    }
+
+    /// Writes a template to the local source called `source`, or the first local one. With
+    /// `overwrite`, a template the source already has with that name is replaced.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_template_in(
+        &self,
+        source: Option<&str>,
+        overwrite: bool,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        config: MemeConfig,
+        name: &str,
+    ) -> Result<(), MemeError> {
+        let source_path = match source {
+            Some(name) => match self.registry.sources().find(|source| source.name() == name) {
+                Some(MemeSource::LocalPath(path)) => Path::new(path),
+                Some(_) => return Err(MemeError::NotLocalSource(name.to_owned())),
+                None => return Err(MemeError::SourceNotFound(name.to_owned())),
+            },
+            None => self
+                .registry
+                .sources()
+                .find_map(|source| match source {
+                    MemeSource::GitUrl { .. } | MemeSource::Embedded => None,
+                    MemeSource::LocalPath(path) => Some(Path::new(path)),
+                })
+                .ok_or(MemeError::NoLocalSource)?,
+        };
 
         let meme_path = source_path.join(name);
-        std::fs::create_dir(&meme_path)?;
+        if overwrite {
+            std::fs::create_dir_all(&meme_path)
+        } else {
+            std::fs::create_dir(&meme_path)
+        }
+        .map_err(MemeError::source_io(&meme_path))?;
         save_buffer(
             meme_path.join("image.png"),
             buf,
@@ -439,8 +1754,13 @@ impl Config {
             height,
             image::ColorType::Rgba8,
         )?;
-        let config = serde_json::to_string_pretty(&config)?;
-        fs::write(meme_path.join("config.json"), config.as_bytes())?;
+        let config_path = meme_path.join("config.json");
+        let config =
+            serde_json::to_string_pretty(&config).map_err(|source| MemeError::TemplateConfig {
+                path: config_path.clone(),
+                source,
+            })?;
+        fs::write(config_path, config.as_bytes())?;
 
         Ok(())
     }
@@ -448,44 +1768,441 @@ impl Config {
     pub fn watermark_size_fraction(&self) -> f32 {
         self.watermark_size_fraction
     }
+
+    /// Whether text should be blended in linear light, see `MemeTemplate::with_linear_blending`
+    pub fn linear_blending(&self) -> bool {
+        self.linear_blending
+    }
+
+    /// The image format (as a file extension) for output that doesn't specify one
+    pub fn default_output_format(&self) -> Option<&str> {
+        self.default_output_format.as_deref()
+    }
+
+    /// How long a git clone or pull may take before it's killed
+    pub fn git_timeout(&self) -> Duration {
+        self.registry.git_timeout()
+    }
+
+    /// A render context with the configured fallback fonts loaded
+    pub fn render_context(&self) -> Result<RenderContext, MemeError> {
+        let mut ctx = RenderContext::new();
+        for path in &self.fallback_fonts {
+            let bytes = fs::read(path).map_err(MemeError::source_io(path))?;
+            ctx = ctx
+                .with_fallback_font(&bytes)
+                .map_err(|reason| MemeError::InvalidFont {
+                    path: path.clone(),
+                    reason,
+                })?;
+        }
+        Ok(ctx)
+    }
+
+    /// Where memes go when no output is given, the clipboard unless configured otherwise
+    pub fn default_output(&self) -> &DefaultOutput {
+        &self.default_output
+    }
+
+    /// Fixes up a cache dir laid out by hand or by older versions. A git source cloned
+    /// straight into the cache dir is moved under its alias, and templates left loose in the
+    /// cache dir, where they're never looked for, are warned about.
+    pub fn migrate_cache(&self) -> Result<(), MemeError> {
+        let cache = cache_dir()?;
+        if !cache.is_dir() {
+            return Ok(());
+        }
+        let aliases: Vec<&str> = self
+            .registry
+            .sources()
+            .filter_map(|source| match source {
+                MemeSource::GitUrl { alias, .. } => Some(alias.as_str()),
+                MemeSource::LocalPath(_) | MemeSource::Embedded => None,
+            })
+            .collect();
+
+        if cache.join(".git").is_dir() {
+            let origin = git_ops::origin_url(&cache);
+            let owner = self.registry.sources().find_map(|source| match source {
+                MemeSource::GitUrl { url, alias, .. } if Some(url) == origin.as_ref() => {
+                    Some(alias)
+                }
+                _ => None,
+            });
+            match owner {
+                Some(alias) if is_missing_or_empty(&cache.join(alias)) => {
+                    log::warn!(
+                        "Moving the clone of {} in {} to {}",
+                        alias,
+                        cache.display(),
+                        cache.join(alias).display()
+                    );
+                    if cache.join(alias).exists() {
+                        fs::remove_dir(cache.join(alias))
+                            .map_err(MemeError::source_io(cache.join(alias)))?;
+                    }
+                    move_entries(&cache, &cache.join(alias), &aliases)?;
+                }
+                _ => log::warn!(
+                    "{} is a git clone, but git sources are cloned into a directory named after \
+                     their alias inside it. Run `meme-cli clean` and `meme-cli update-sources` \
+                     to clone them again.",
+                    cache.display()
+                ),
+            }
+        }
+
+        for entry in cache.read_dir().map_err(MemeError::source_io(&cache))? {
+            let entry = entry.map_err(MemeError::source_io(&cache))?;
+            let name = entry.file_name();
+            if aliases.iter().any(|alias| name == **alias) {
+                continue;
+            }
+            if entry.path().join("config.json").is_file() {
+                log::warn!(
+                    "{} looks like a template, but templates in {} aren't used. \
+                     Move it into a local source instead.",
+                    entry.path().display(),
+                    cache.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Older versions made a source's directory before cloning into it, so an empty one counts as missing
+fn is_missing_or_empty(path: &Path) -> bool {
+    match path.read_dir() {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => !path.exists(),
+    }
+}
+
+/// Moves everything in `from` into the new directory `to`, except the entries named in `keep`
+fn move_entries(from: &Path, to: &Path, keep: &[&str]) -> Result<(), MemeError> {
+    let entries = from
+        .read_dir()
+        .map_err(MemeError::source_io(from))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(MemeError::source_io(from))?;
+    fs::create_dir(to).map_err(MemeError::source_io(to))?;
+    for entry in entries {
+        let name = entry.file_name();
+        if keep.iter().any(|keep| name == **keep) {
+            continue;
+        }
+        fs::rename(entry.path(), to.join(&name)).map_err(MemeError::source_io(entry.path()))?;
+    }
+    Ok(())
+}
+
+/// Loads a template from a directory containing its `config.json` and image
+pub fn load_template_from_dir(dir_path: &Path) -> Result<MemeTemplate, MemeError> {
+    let config_path = dir_path.join("config.json");
+    let config = fs::read(&config_path).map_err(MemeError::source_io(&config_path))?;
+    let mut config = parse_template_config(dir_path, &config)?;
+
+    if let Some(layout) = config.panels.take() {
+        return load_panel_template(dir_path, config, layout, |file| {
+            read_template_file(dir_path, file)
+        });
+    }
+
+    let image_path = find_template_image(dir_path)?;
+    let image = fs::read(&image_path).map_err(MemeError::source_io(&image_path))?;
+
+    load_template(dir_path, config, &image)
+}
+
+fn read_template_file(dir_path: &Path, file: &str) -> Result<Vec<u8>, MemeError> {
+    let path = dir_path.join(file);
+    fs::read(&path).map_err(MemeError::source_io(path))
+}
+
+/// The template's image: `image.png` if there is one, otherwise the first other `image.*`
+/// in a format `image` can read, like `image.webp` or `image.jpg`
+fn find_template_image(dir_path: &Path) -> Result<PathBuf, MemeError> {
+    let png_path = dir_path.join("image.png");
+    if png_path.is_file() {
+        return Ok(png_path);
+    }
+    let mut candidates: Vec<_> = dir_path
+        .read_dir()
+        .map_err(MemeError::source_io(dir_path))?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| is_template_image(path))
+        .collect();
+    candidates.sort();
+    // still name image.png, for a not found error that points at the usual file
+    Ok(candidates.into_iter().next().unwrap_or(png_path))
+}
+
+/// Whether `path` is named like a template image, `image.` and the extension of a format
+/// `image` is built to decode. AVIF isn't one, its decoder needs the dav1d C library.
+pub(crate) fn is_template_image(path: &Path) -> bool {
+    path.file_stem() == Some("image".as_ref())
+        && matches!(
+            ImageFormat::from_path(path),
+            Ok(ImageFormat::Png
+                | ImageFormat::Jpeg
+                | ImageFormat::Gif
+                | ImageFormat::WebP
+                | ImageFormat::Bmp
+                | ImageFormat::Ico
+                | ImageFormat::Tiff
+                | ImageFormat::Tga
+                | ImageFormat::Pnm
+                | ImageFormat::Hdr
+                | ImageFormat::Farbfeld)
+        )
+}
+
+pub(crate) fn parse_template_config(
+    dir_path: &Path,
+    config: &[u8],
+) -> Result<MemeConfig, MemeError> {
+    serde_json::from_slice(config).map_err(|source| MemeError::TemplateConfig {
+        path: dir_path.join("config.json"),
+        source,
+    })
+}
+
+/// Loads a template from its config and the contents of its image, whose format is
+/// guessed from its contents. `dir_path` is only used to say which template is broken.
+pub(crate) fn load_template(
+    dir_path: &Path,
+    config: MemeConfig,
+    image: &[u8],
+) -> Result<MemeTemplate, MemeError> {
+    let image = image::load_from_memory(image)?.into_rgba8();
+    build_template(dir_path, config, image)
+}
+
+/// Loads a template made of panels, flattening them into a single image with the panels'
+/// boxes added to the template's own. `read_file` reads a panel's image by name.
+pub(crate) fn load_panel_template(
+    dir_path: &Path,
+    mut config: MemeConfig,
+    layout: PanelLayout,
+    read_file: impl Fn(&str) -> Result<Vec<u8>, MemeError>,
+) -> Result<MemeTemplate, MemeError> {
+    let images = layout
+        .panels
+        .iter()
+        .map(|panel| Ok(image::load_from_memory(&read_file(&panel.image)?)?.into_rgba8()))
+        .collect::<Result<Vec<_>, MemeError>>()?;
+    let sizes: Vec<_> = images.iter().map(|image| image.dimensions()).collect();
+    let (positions, (width, height)) = layout.arrange(&sizes);
+
+    let mut image = RgbaImage::new(width, height);
+    for (panel, &(x, y)) in images.iter().zip(&positions) {
+        image::imageops::replace(&mut image, panel, x, y);
+    }
+    config.text.extend(layout.fields(&positions));
+
+    build_template(dir_path, config, image)
+}
+
+/// Checks a template's config fits its image before making the template
+fn build_template(
+    dir_path: &Path,
+    config: MemeConfig,
+    image: RgbaImage,
+) -> Result<MemeTemplate, MemeError> {
+    validate_template(&config, &image).map_err(|reason| MemeError::InvalidTemplate {
+        path: dir_path.to_owned(),
+        reason,
+    })?;
+
+    Ok(MemeTemplate {
+        config,
+        image,
+        resize_filter: FilterType::Lanczos3,
+        linear_blending: false,
+    })
+}
+
+/// Checks the invariants rendering relies on, describing every one that's broken
+fn validate_template(config: &MemeConfig, image: &RgbaImage) -> Result<(), String> {
+    let mut problems = vec![];
+    if config.text.is_empty() {
+        problems.push("it has no text boxes".to_owned());
+    }
+    let (width, height) = image.dimensions();
+    for (i, field) in config.text.iter().enumerate() {
+        if field.min.0 >= field.max.0 || field.min.1 >= field.max.1 {
+            problems.push(format!(
+                "text box {} has its min corner {:?} past its max corner {:?}",
+                i, field.min, field.max
+            ));
+        }
+        if field.max.0 > width || field.max.1 > height {
+            problems.push(format!(
+                "text box {} reaches {:?}, outside the {}x{} image",
+                i, field.max, width, height
+            ));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+/// The directory git sources are cloned into: `$MEMECLI_CACHE_DIR` if it's set,
+/// `memecli` in the user's cache dir otherwise
+pub fn cache_dir() -> Result<PathBuf, MemeError> {
+    if let Some(dir) = std::env::var_os("MEMECLI_CACHE_DIR") {
+        return Ok(dir.into());
+    }
+    Ok(dirs::cache_dir()
+        .ok_or(MemeError::DirNotFound("cache"))?
+        .join("memecli"))
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum MemeSource {
-    GitUrl { url: String, alias: String },
+    GitUrl {
+        url: String,
+        alias: String,
+        /// Only fetch templates as they're used, for very large repositories
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        sparse: bool,
+    },
     LocalPath(String),
+    /// The read-only templates compiled in with the `embedded` feature
+    Embedded,
 }
 
 impl MemeSource {
-    pub fn to_path_and_update(&self) -> Result<PathBuf, Error> {
-        let cache = dirs::cache_dir()
-            .ok_or_else(|| anyhow!("cache dir not found"))?
-            .join("memecli");
+    /// What the source is called in messages and `--source`: a git source's alias, a local
+    /// source's path, or `embedded`
+    pub fn name(&self) -> &str {
+        match self {
+            MemeSource::GitUrl { alias, .. } => alias,
+            MemeSource::LocalPath(path) => path,
+            MemeSource::Embedded => "embedded",
+        }
+    }
+
+    /// Updates git sources, giving up after `timeout`, and returns where the source's
+    /// templates are. Embedded sources have no path, and never need updating.
+    pub fn to_path_and_update(&self, timeout: Duration) -> Result<Option<PathBuf>, MemeError> {
+        let cache = cache_dir()?;
         let path = match self {
-            MemeSource::GitUrl { url, alias } => {
-                let path = cache.join(&alias);
+            MemeSource::GitUrl { url, alias, sparse } => {
+                let path = cache.join(alias);
                 if path.is_dir() && path.read_dir()?.next().is_some() {
-                    eprintln!("Updating meme repository {} ({})", alias, url);
-                    git_ops::update_repo(&path)?;
+                    log::info!("Updating meme repository {} ({})", alias, url);
+                    git_ops::update_repo(&path, timeout)?;
                 } else {
-                    eprintln!("Cloning meme repository {} ({})", alias, url);
+                    log::info!("Cloning meme repository {} ({})", alias, url);
                     fs::create_dir_all(&path)?;
-                    git_ops::clone_repo(&path, url)?;
+                    if !*sparse {
+                        git_ops::clone_repo(&path, url, timeout)?;
+                    } else if let Err(e) = git_ops::clone_repo_sparse(&path, url, timeout) {
+                        log::warn!("{}, falling back to a full clone", e);
+                        fs::create_dir_all(&path)?;
+                        git_ops::clone_repo(&path, url, timeout)?;
+                    }
                 }
                 path
             }
             MemeSource::LocalPath(path) => PathBuf::from(path),
+            MemeSource::Embedded => return Ok(None),
         };
-        Ok(path)
+        Ok(Some(path))
     }
 
-    pub fn to_path(&self) -> Result<PathBuf, Error> {
-        let cache = dirs::cache_dir().unwrap().join("memecli");
-        let source = match self {
-            MemeSource::GitUrl { alias, .. } => cache.join(alias),
-            MemeSource::LocalPath(path) => PathBuf::from(path),
+    /// Where a git source is cloned to, or `None` for local sources
+    pub fn cache_path(&self) -> Result<Option<PathBuf>, MemeError> {
+        match self {
+            MemeSource::GitUrl { alias, .. } => {
+                let mut components = Path::new(alias).components();
+                match (components.next(), components.next()) {
+                    (Some(Component::Normal(_)), None) => Ok(Some(cache_dir()?.join(alias))),
+                    _ => Err(MemeError::InvalidAlias(alias.clone())),
+                }
+            }
+            MemeSource::LocalPath(_) | MemeSource::Embedded => Ok(None),
+        }
+    }
+
+    /// Where the source's templates are, or `None` for the embedded source.
+    /// A git source's directory only exists once it's been cloned.
+    pub fn to_path(&self) -> Result<Option<PathBuf>, MemeError> {
+        match self {
+            MemeSource::GitUrl { alias, .. } => Ok(Some(cache_dir()?.join(alias))),
+            MemeSource::LocalPath(path) => {
+                let source = PathBuf::from(path);
+                fs::create_dir_all(&source).map_err(MemeError::source_io(&source))?;
+                Ok(Some(source))
+            }
+            MemeSource::Embedded => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_composites_onto_transparency() {
+        let mut image = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 128]));
+        let mask = GrayImage::from_pixel(1, 1, Luma([128]));
+        simple_overlay(&mut image, &mask, [1., 1., 1., 1.], (0, 0), false);
+        assert_eq!(*image.get_pixel(0, 0), Rgba([170, 170, 255, 192]));
+    }
+
+    #[test]
+    fn linear_blending_lightens_edges() {
+        let edge = |linear| {
+            let mut image = RgbaImage::from_pixel(1, 1, Rgba([255; 4]));
+            let mask = GrayImage::from_pixel(1, 1, Luma([128]));
+            simple_overlay(&mut image, &mask, [0., 0., 0., 1.], (0, 0), linear);
+            *image.get_pixel(0, 0)
         };
-        fs::create_dir_all(&source)?;
-        Ok(source)
+        let (srgb, linear) = (edge(false), edge(true));
+        assert!(linear.0[0] > srgb.0[0]);
+        assert_eq!(linear.0[3], srgb.0[3]);
+    }
+
+    #[test]
+    fn overlong_text_is_clipped() {
+        let field = MemeField {
+            min: (0, 0),
+            max: (400, 100),
+            ..Default::default()
+        };
+        let mask =
+            RenderContext::new().text_mask(&field, FillMode::Fit, &"WORD ".repeat(2000), 600.);
+        assert_eq!(mask.dimensions(), (400, 100));
+    }
+
+    #[test]
+    fn height_fraction_shrinks_wrapped_text() {
+        let field = MemeField {
+            min: (0, 0),
+            max: (200, 40),
+            ..Default::default()
+        };
+        let mut ctx = RenderContext::new();
+        let text = "when you finally fix the bug";
+        let (_, fit) = get_filling_glyphs(
+            &field,
+            FillMode::HeightFraction(0.8),
+            &ctx.fonts,
+            &mut ctx.layout,
+            MIN_FONT_SIZE,
+            600.,
+            text,
+        );
+        assert!(fit.fits);
+        assert!(fit.font_size < 40. * 0.8);
+        ctx.text_mask(&field, FillMode::HeightFraction(0.8), text, 600.);
     }
 }
@@ -7,3 +7,15 @@ pub use self::arboard::*;
 mod termux;
 #[cfg(target_os = "android")]
 pub use termux::*;
+
+#[cfg(all(feature = "screenshot", not(target_os = "android")))]
+mod screenshot;
+#[cfg(all(feature = "screenshot", not(target_os = "android")))]
+pub use self::screenshot::screenshot;
+
+#[cfg(all(feature = "screenshot", target_os = "android"))]
+pub fn screenshot(
+    _region: Option<((u32, u32), (u32, u32))>,
+) -> Result<image::RgbaImage, anyhow::Error> {
+    Err(anyhow::anyhow!("Screenshots aren't supported on this platform."))
+}
@@ -1,24 +1,78 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, thread, time::Duration};
 
-use anyhow::{anyhow, Error};
-use arboard::ImageData;
+use anyhow::{anyhow, Context, Error};
+use arboard::{Clipboard, ImageData};
 use image::RgbaImage;
 
-pub fn image_out(img_buffer: RgbaImage) -> Result<(), Error> {
-    let mut clipboard = arboard::Clipboard::new()?;
-    clipboard.set_image(ImageData {
-        width: img_buffer.width() as _,
-        height: img_buffer.height() as _,
-        bytes: Cow::Borrowed(&img_buffer),
-    })?;
+const ATTEMPTS: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
 
-    Ok(())
+pub fn image_out(img_buffer: &RgbaImage) -> Result<(), Error> {
+    with_retries(|clipboard| {
+        clipboard.set_image(ImageData {
+            width: img_buffer.width() as _,
+            height: img_buffer.height() as _,
+            bytes: Cow::Borrowed(img_buffer),
+        })
+    })
+    .context("Can't put the meme on the clipboard, try --output to save it to a file instead")
 }
 
 pub fn image_in() -> Result<RgbaImage, Error> {
-    let mut clipboard = arboard::Clipboard::new()?;
-    clipboard.get_image().map_err(Error::from).and_then(|img| {
-        image::RgbaImage::from_raw(img.width as u32, img.height as u32, img.bytes.into_owned())
-            .ok_or(anyhow!("image from clipboard not compatible"))
-    })
+    let img = with_retries(|clipboard| clipboard.get_image().map(|img| img.to_owned_img()))
+        .context("Can't get an image from the clipboard, try passing it with --input")?;
+    let len = img.bytes.len();
+    let bytes = to_packed_rgba(img.width, img.height, img.bytes).ok_or_else(|| {
+        anyhow!(
+            "image from clipboard not compatible ({}x{}, {} bytes), try passing it with --input",
+            img.width,
+            img.height,
+            len
+        )
+    })?;
+    image::RgbaImage::from_raw(img.width as u32, img.height as u32, bytes)
+        .ok_or(anyhow!("image from clipboard not compatible"))
+}
+
+/// Other apps can hold the clipboard for a moment, so give them a few chances to let go
+fn with_retries<T>(
+    mut f: impl FnMut(&mut Clipboard) -> Result<T, arboard::Error>,
+) -> Result<T, arboard::Error> {
+    let mut attempt = 1;
+    loop {
+        match Clipboard::new().and_then(|mut clipboard| f(&mut clipboard)) {
+            Err(arboard::Error::ClipboardOccupied) if attempt < ATTEMPTS => {
+                attempt += 1;
+                thread::sleep(RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// arboard promises tightly packed RGBA, but some platforms hand back padded rows or RGB pixels
+fn to_packed_rgba(width: usize, height: usize, bytes: Cow<[u8]>) -> Option<Vec<u8>> {
+    if bytes.len() == width * height * 4 {
+        return Some(bytes.into_owned());
+    }
+    if height == 0 || !bytes.len().is_multiple_of(height) {
+        return None;
+    }
+    let stride = bytes.len() / height;
+    let channels = if stride >= width * 4 {
+        4
+    } else if stride >= width * 3 {
+        3
+    } else {
+        return None;
+    };
+
+    let mut packed = Vec::with_capacity(width * height * 4);
+    for row in bytes.chunks_exact(stride) {
+        for pixel in row[..width * channels].chunks_exact(channels) {
+            packed.extend_from_slice(&pixel[..3]);
+            packed.push(if channels == 4 { pixel[3] } else { u8::MAX });
+        }
+    }
+    Some(packed)
 }
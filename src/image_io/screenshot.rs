@@ -0,0 +1,26 @@
+use anyhow::{anyhow, Error};
+use image::RgbaImage;
+use screenshots::Screen;
+
+/// Captures the primary screen, or the `(min, max)` region of it if given.
+pub fn screenshot(region: Option<((u32, u32), (u32, u32))>) -> Result<RgbaImage, Error> {
+    let screens = Screen::all()?;
+    let screen = screens
+        .iter()
+        .find(|screen| screen.display_info.is_primary)
+        .or_else(|| screens.first())
+        .ok_or_else(|| anyhow!("No screen to capture"))?;
+    let captured = match region {
+        Some(((left, top), (right, bottom))) => screen.capture_area(
+            left as i32,
+            top as i32,
+            right.saturating_sub(left),
+            bottom.saturating_sub(top),
+        )?,
+        None => screen.capture()?,
+    };
+    // `screenshots` uses a newer `image`, so move the pixels across by hand
+    let (width, height) = captured.dimensions();
+    RgbaImage::from_raw(width, height, captured.into_raw())
+        .ok_or_else(|| anyhow!("Screenshot has an unexpected size"))
+}
@@ -1,20 +1,53 @@
 use std::process::Command;
 
 use anyhow::{anyhow, Error};
-use image::RgbaImage;
+use image::{ImageOutputFormat, RgbaImage};
 
 pub fn image_out(img_buffer: &RgbaImage) -> Result<(), Error> {
-    let img_path = std::env::temp_dir().join("share.png");
-    img_buffer.save(&img_path)?;
-    Command::new("termux-share")
-        .args(["-a", "send", img_path.to_str().unwrap()])
-        .spawn()?
-        .wait()?
-        .success()
-        .then(|| ())
-        .ok_or(anyhow!("termux-send error!"))
+    // a fresh file per call, so concurrent shares don't clobber each other.
+    // it's removed when `img_file` is dropped.
+    let mut img_file = tempfile::Builder::new()
+        .prefix("meme-")
+        .suffix(".png")
+        .tempfile()?;
+    image::DynamicImage::ImageRgba8(img_buffer.clone())
+        .write_to(img_file.as_file_mut(), ImageOutputFormat::Png)?;
+    let img_path = img_file
+        .path()
+        .to_str()
+        .ok_or_else(|| anyhow!("Temporary file path isn't valid UTF-8"))?;
+    let output = Command::new("termux-share")
+        .args(["-a", "send", img_path])
+        .output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "termux-share failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
 }
 
+/// Asks for an image with Android's file picker
 pub fn image_in() -> Result<RgbaImage, Error> {
-    Err(anyhow!("This isn't supported on termux."))
+    let img_file = tempfile::Builder::new().prefix("meme-").tempfile()?;
+    let img_path = img_file
+        .path()
+        .to_str()
+        .ok_or_else(|| anyhow!("Temporary file path isn't valid UTF-8"))?;
+    let output = Command::new("termux-storage-get").arg(img_path).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "termux-storage-get failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let bytes = std::fs::read(img_file.path())?;
+    if bytes.is_empty() {
+        return Err(anyhow!(
+            "No image was picked. Pass --input to use a file instead."
+        ));
+    }
+    Ok(image::load_from_memory(&bytes)?.into_rgba8())
 }
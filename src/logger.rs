@@ -0,0 +1,37 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Prints log messages to stderr. Progress messages at info level are printed as is,
+/// everything else is tagged with its level.
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Info => eprintln!("{}", record.args()),
+            level => eprintln!("[{}] {}", level, record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Logs info and up by default, debug with `-v` and everything with `-vv`
+pub fn init(verbosity: u8) {
+    let level = match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    // only fails if a logger is already set, and this is the only place that sets one
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
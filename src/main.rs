@@ -1,28 +1,64 @@
-use std::path::PathBuf;
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::{anyhow, Error};
-use image::EncodableLayout;
-use image::Rgba;
-use memeinator::{Config, MemeConfig, MemeContent, MemeField};
+use anyhow::{anyhow, Context, Error};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    imageops::{ColorMap, FilterType},
+    Delay, DynamicImage, Frame, ImageFormat, Rgba, RgbaImage,
+};
+use memeinator::{
+    Config, DefaultOutput, FillMode, Filter, MemeConfig, MemeContent, MemeField, MemeTemplate,
+    RenderContext,
+};
+use notify::{RecursiveMode, Watcher};
+use serde::Deserialize;
 use structopt::{clap::Shell, StructOpt};
 use std::str::FromStr;
 
 mod image_io;
+mod logger;
+#[cfg(feature = "server")]
+mod serve;
 
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "meme-cli",
     about = "A way to easily generate dank memes from preconfigured templates"
 )]
+struct Cli {
+    /// Print more about what's going on, like where templates are looked for.
+    /// Repeat it (`-vv`) for even more.
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+
+    #[structopt(subcommand)]
+    command: Opt,
+}
+
+#[derive(Debug, StructOpt)]
+// parsed once at startup, so the size of `Generate` doesn't matter
+#[allow(clippy::large_enum_variant)]
 enum Opt {
     Generate(Generate),
     MakeTemplate(MakeTemplate),
+    Batch(Batch),
     #[structopt(about = "List all template sources")]
     ListSources,
     #[structopt(about = "List all template names")]
-    ListTemplates,
+    ListTemplates(ListTemplates),
     #[structopt(about = "Fetch potential new memes from the configured sources")]
-    UpdateSources,
+    UpdateSources(UpdateSources),
+    Where(Where),
+    Clean(Clean),
+    ValidateTemplate(ValidateTemplate),
+    Search(Search),
+    #[cfg(feature = "server")]
+    Serve(serve::Serve),
     #[structopt(about = "Generates a basic completion script")]
     GenerateProtoCompletions(GenerateProtoCompletions),
 }
@@ -48,24 +84,161 @@ impl FromStr for Rgba8 {
     }
 }
 
+/// Text for the box called `name`, given as `NAME=TEXT`
+#[derive(Debug)]
+struct NamedInput {
+    name: String,
+    text: String,
+}
+
+impl FromStr for NamedInput {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (name, text) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=TEXT, got {}", s))?;
+        Ok(NamedInput {
+            name: name.to_owned(),
+            text: text.to_owned(),
+        })
+    }
+}
+
+/// Content and overrides for one box, an entry of `--json-inputs`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonInput {
+    #[serde(default)]
+    text: String,
+    /// An image to paste into the box instead of text
+    image: Option<PathBuf>,
+    /// A CSS color for the box's text
+    color: Option<String>,
+    max_font_size: Option<f32>,
+}
+
+/// The boxes' content as a JSON array, in the same order as the inputs
+#[derive(Debug)]
+struct JsonInputs(Vec<JsonInput>);
+
+impl FromStr for JsonInputs {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        serde_json::from_str(s)
+            .map(JsonInputs)
+            .map_err(|e| format!("error parsing JSON inputs: {}", e))
+    }
+}
+
+/// An image placed into the box at `index`, given as `INDEX:PATH`
+#[derive(Debug)]
+struct ImageBox {
+    index: usize,
+    path: PathBuf,
+}
+
+impl FromStr for ImageBox {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (index, path) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected INDEX:PATH, got {}", s))?;
+        let index = index
+            .parse()
+            .map_err(|x| format!("error parsing box index: {}", x))?;
+        Ok(ImageBox {
+            index,
+            path: path.into(),
+        })
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "gen", about = "Generate a meme from a template")]
 struct Generate {
     /// The template to use
-    template: String,
+    #[structopt(required_unless = "template-dir")]
+    template: Option<String>,
     /// The text placed into the template
     inputs: Vec<String>,
 
-    /// The output path for the meme. By default, the meme will be pushed to the clipboard.
+    /// Load the template from this directory instead of the configured sources.
+    /// All positional arguments are then used as inputs.
+    #[structopt(long)]
+    template_dir: Option<PathBuf>,
+
+    /// Only look for the template in this source: a git source's alias, a local source's path,
+    /// or `embedded`
+    #[structopt(long, conflicts_with = "template-dir")]
+    source: Option<String>,
+
+    /// The boxes' content as a JSON array with an object per box, like
+    /// `[{"text": "top", "color": "red", "max_font_size": 80}, {"image": "cat.png"}]`.
+    /// Can't be combined with inputs on the command line or `--text-file`.
+    #[structopt(long, conflicts_with_all = &["inputs", "text-file"])]
+    json_inputs: Option<JsonInputs>,
+
+    /// Read captions from a file, one per blank-line-separated paragraph.
+    /// These are placed after any inputs given on the command line.
+    #[structopt(long)]
+    text_file: Option<PathBuf>,
+
+    /// The output path for the meme. By default, the meme will be pushed to the clipboard,
+    /// or wherever the config's `default_output` says.
     /// Setting this to `-` will redirect output to stdout as a png.
     #[structopt(short, long)]
     output: Option<PathBuf>,
 
+    /// The image format to write, e.g. `png` or `jpg`. Defaults to the output file's extension,
+    /// then to the configured default format, then to png.
+    #[structopt(short, long, parse(try_from_str = parse_format))]
+    format: Option<ImageFormat>,
+
+    /// Dithering for GIF output, `none` or `floyd-steinberg`. Dithering smooths out gradients,
+    /// but adds noise to flat colors.
+    #[structopt(long, default_value = "none")]
+    dither: Dither,
+
+    /// Write a GIF of the meme shaking, jumping by up to this many pixels between frames
+    #[structopt(long, requires = "output")]
+    shake: Option<u32>,
+
+    /// How many frames the `--shake` GIF loops through
+    #[structopt(long, default_value = "8", parse(try_from_str = parse_nonzero))]
+    shake_frames: u32,
+
+    /// Fit one palette to all the `--shake` frames instead of one per frame, for a smaller GIF
+    #[structopt(long, requires = "shake")]
+    global_palette: bool,
+
+    /// Render again whenever the template's directory changes, overwriting `--output`
+    #[structopt(long, requires = "output")]
+    watch: bool,
+
+    /// Draw the meme onto this image instead of making a new one, growing the image if the meme
+    /// doesn't fit. The result is written back to it unless `--output` is given.
+    #[structopt(long)]
+    append_to: Option<PathBuf>,
+
+    /// Where `--append-to` places the meme's top left corner, as `X,Y`. Defaults to `0,0`.
+    #[structopt(long, requires = "append-to", parse(try_from_str = parse_position))]
+    at: Option<(u32, u32)>,
+
+    /// Also push the meme to the clipboard when writing it to `--output`.
+    /// The clipboard can't hold a `--shake` GIF, so the two can't be combined.
+    #[structopt(long, requires = "output", conflicts_with = "shake")]
+    also_copy: bool,
+
     /// The maximum font size for the text. Defaults to 600.
     #[structopt(short, long)]
     max_size: Option<f32>,
 
-    // Set a custom watermark
+    /// The most lines each caption may wrap into, overriding the template
+    #[structopt(long)]
+    max_lines: Option<usize>,
+
+    /// Set a custom watermark. Without a value, the configured watermark is used even if the
+    /// template overrides it. An empty value disables the watermark.
     #[structopt(short, long)]
     watermark: Option<Option<String>>,
 
@@ -73,7 +246,314 @@ struct Generate {
     top_text: Option<String>,
 
     #[structopt(short, long)]
-    color: Option<Rgba8>
+    color: Option<Rgba8>,
+
+    /// Print the font size and line count chosen for each box, and whether its text fit
+    #[structopt(long)]
+    measure: bool,
+
+    /// Fail instead of clipping text that doesn't fit its box even at the smallest size
+    #[structopt(long)]
+    strict: bool,
+
+    /// Save the mask each box's text and the watermark are drawn with into this directory,
+    /// as `box-<index>.png` and `watermark.png`
+    #[structopt(long)]
+    debug_dump: Option<PathBuf>,
+
+    /// Post-process the meme with `grayscale`, `sepia`, `invert` or `deepfry`
+    #[structopt(long)]
+    filter: Option<Filter>,
+
+    /// Dither the meme down to pure black and white, for e-ink screens and thermal printers.
+    /// Applied after `--filter`.
+    #[structopt(long)]
+    mono: bool,
+
+    /// Outline the template's text boxes on the output, to check their coordinates
+    #[structopt(long)]
+    print_boxes: bool,
+
+    /// Text for a box the template names, given as `NAME=TEXT`
+    #[structopt(long = "box", number_of_values = 1)]
+    named_inputs: Vec<NamedInput>,
+
+    /// How images are resized to fit: `nearest`, `triangle` or `lanczos3`.
+    /// Nearest keeps pixel art crisp. Defaults to lanczos3.
+    #[structopt(long, parse(try_from_str = parse_filter_type))]
+    filter_type: Option<FilterType>,
+
+    /// Paste an image into a box instead of text, given as `INDEX:PATH`.
+    /// Boxes are counted from 0, in the same order as the inputs.
+    #[structopt(long, number_of_values = 1)]
+    image_box: Vec<ImageBox>,
+
+    /// An image placed under the template, for templates with transparency
+    #[structopt(long, conflicts_with = "background-color")]
+    background_image: Option<PathBuf>,
+
+    /// A solid color placed under the template, for templates with transparency
+    #[structopt(long)]
+    background_color: Option<Rgba8>,
+}
+
+fn parse_format(format: &str) -> Result<ImageFormat, Error> {
+    ImageFormat::from_extension(format).ok_or_else(|| anyhow!("Unknown image format {}", format))
+}
+
+fn parse_position(position: &str) -> Result<(u32, u32), Error> {
+    let (x, y) = position
+        .split_once(',')
+        .ok_or_else(|| anyhow!("Expected X,Y, got {}", position))?;
+    Ok((x.trim().parse()?, y.trim().parse()?))
+}
+
+fn parse_nonzero(value: &str) -> Result<u32, Error> {
+    match value.parse()? {
+        0 => Err(anyhow!("Expected at least 1")),
+        value => Ok(value),
+    }
+}
+
+fn parse_filter_type(filter: &str) -> Result<FilterType, Error> {
+    match filter {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" => Ok(FilterType::Triangle),
+        "lanczos3" => Ok(FilterType::Lanczos3),
+        _ => Err(anyhow!("Unknown filter type {}", filter)),
+    }
+}
+
+fn write_image(img: &RgbaImage, format: ImageFormat, w: &mut impl Write) -> Result<(), Error> {
+    write_dynamic_image(DynamicImage::ImageRgba8(img.clone()), format, w)
+}
+
+fn write_dynamic_image(
+    img: DynamicImage,
+    format: ImageFormat,
+    w: &mut impl Write,
+) -> Result<(), Error> {
+    // jpeg has no alpha channel
+    let img = if format == ImageFormat::Jpeg && img.color().has_alpha() {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    } else {
+        img
+    };
+    img.write_to(w, format)?;
+    Ok(())
+}
+
+/// How colors are reduced to a 256 color palette for GIF output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dither {
+    None,
+    FloydSteinberg,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Dither::None),
+            "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+            _ => Err(format!("expected none or floyd-steinberg, got {}", s)),
+        }
+    }
+}
+
+/// A 256 color palette fitted to an image
+struct Palette(color_quant::NeuQuant);
+
+impl ColorMap for Palette {
+    type Color = Rgba<u8>;
+
+    fn index_of(&self, color: &Rgba<u8>) -> usize {
+        self.0.index_of(&color.0)
+    }
+
+    fn map_color(&self, color: &mut Rgba<u8>) {
+        self.0.map_pixel(&mut color.0);
+    }
+}
+
+/// Reduces the image to a 256 color palette, diffusing the error to hide banding
+fn floyd_steinberg(img: &RgbaImage) -> RgbaImage {
+    let palette = Palette(color_quant::NeuQuant::new(10, 256, img.as_raw()));
+    let mut dithered = img.clone();
+    image::imageops::dither(&mut dithered, &palette);
+    dithered
+}
+
+/// Draws `meme` onto `canvas` with its top left corner at `(x, y)`, growing the canvas with
+/// transparent pixels if the meme doesn't fit
+fn append_onto(
+    canvas: RgbaImage,
+    meme: &RgbaImage,
+    (x, y): (u32, u32),
+) -> Result<RgbaImage, Error> {
+    let too_far = || anyhow!("--at {},{} is too far out to fit the meme", x, y);
+    let width = canvas
+        .width()
+        .max(x.checked_add(meme.width()).ok_or_else(too_far)?);
+    let height = canvas
+        .height()
+        .max(y.checked_add(meme.height()).ok_or_else(too_far)?);
+    let mut canvas = if canvas.dimensions() == (width, height) {
+        canvas
+    } else {
+        let mut grown = RgbaImage::new(width, height);
+        image::imageops::replace(&mut grown, &canvas, 0, 0);
+        grown
+    };
+    image::imageops::overlay(&mut canvas, meme, x, y);
+    Ok(canvas)
+}
+
+/// Copies of `img` shifted by random offsets of up to `intensity` pixels, with the edges
+/// stretched over the uncovered side
+fn shake_frames(img: &RgbaImage, intensity: u32, count: u32) -> Vec<RgbaImage> {
+    // a fixed seed makes the same meme shake the same way every time
+    let mut state = 0x2545_f491_u32;
+    let mut offset = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        // in i64, so no --shake is too big for the range
+        state as i64 % (2 * intensity as i64 + 1) - intensity as i64
+    };
+    let (width, height) = img.dimensions();
+    (0..count)
+        .map(|_| {
+            let (dx, dy) = (offset(), offset());
+            RgbaImage::from_fn(width, height, |x, y| {
+                let x = (x as i64 + dx).clamp(0, width as i64 - 1) as u32;
+                let y = (y as i64 + dy).clamp(0, height as i64 - 1) as u32;
+                *img.get_pixel(x, y)
+            })
+        })
+        .collect()
+}
+
+/// Fails if text doesn't fit its box even at the smallest font size. Without `--strict`,
+/// such text is clipped to its box instead.
+fn check_fits(
+    meme: &MemeTemplate,
+    ctx: &mut RenderContext,
+    inputs: &[MemeContent],
+    max_font_size: f32,
+) -> Result<(), Error> {
+    let overflowing = meme.overflowing_fields(ctx, inputs, max_font_size);
+    if !overflowing.is_empty() {
+        return Err(anyhow!(
+            "Text doesn't fit in box(es) {:?}, even at the smallest font size",
+            overflowing
+        ));
+    }
+    Ok(())
+}
+
+/// Resolves `--watermark` against the template's and the configured watermark
+fn pick_watermark<'a>(
+    arg: Option<&'a Option<String>>,
+    template: Option<Option<&'a str>>,
+    configured: &'a str,
+) -> Option<&'a str> {
+    match arg {
+        Some(Some(watermark)) if watermark.is_empty() => None,
+        Some(Some(watermark)) => Some(watermark),
+        Some(None) => Some(configured),
+        None => template.unwrap_or(Some(configured)),
+    }
+}
+
+fn write_gif(frames: Vec<RgbaImage>, w: &mut impl Write) -> Result<(), Error> {
+    let mut encoder = GifEncoder::new(w);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(
+        frames
+            .into_iter()
+            .map(|frame| Frame::from_parts(frame, 0, 0, Delay::from_numer_denom_ms(40, 1))),
+    )?;
+    Ok(())
+}
+
+/// Like `write_gif`, but fits one palette to all the frames and stores it once, instead of
+/// fitting and storing a palette per frame
+fn write_gif_global_palette(
+    frames: &[RgbaImage],
+    dither: Dither,
+    w: &mut impl Write,
+) -> Result<(), Error> {
+    let (width, height) = match frames.first() {
+        Some(frame) => frame.dimensions(),
+        None => return Err(anyhow!("A GIF needs at least one frame")),
+    };
+    let too_large = || anyhow!("{}x{} is too large for a GIF", width, height);
+    let gif_width = u16::try_from(width).map_err(|_| too_large())?;
+    let gif_height = u16::try_from(height).map_err(|_| too_large())?;
+
+    let pixels: Vec<u8> = frames
+        .iter()
+        .flat_map(|frame| frame.iter().copied())
+        .collect();
+    let palette = Palette(color_quant::NeuQuant::new(10, 256, &pixels));
+    let colors = palette.0.color_map_rgba();
+    let rgb: Vec<u8> = colors
+        .chunks_exact(4)
+        .flat_map(|color| &color[..3])
+        .copied()
+        .collect();
+    // GIF has no partial transparency, so the most transparent color stands in for all of it
+    let transparent = colors
+        .chunks_exact(4)
+        .enumerate()
+        .min_by_key(|(_, color)| color[3])
+        .filter(|(_, color)| color[3] < 128)
+        .map(|(i, _)| i as u8);
+
+    let mut encoder = gif::Encoder::new(w, gif_width, gif_height, &rgb)?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    for frame in frames {
+        let mut frame = frame.clone();
+        if dither == Dither::FloydSteinberg {
+            image::imageops::dither(&mut frame, &palette);
+        }
+        let indices: Vec<u8> = frame
+            .pixels()
+            .map(|pixel| palette.index_of(pixel) as u8)
+            .collect();
+        encoder.write_frame(&gif::Frame {
+            width: gif_width,
+            height: gif_height,
+            buffer: indices.into(),
+            delay: 4,
+            dispose: gif::DisposalMethod::Background,
+            transparent,
+            ..Default::default()
+        })?;
+    }
+    Ok(())
+}
+
+fn read_paragraphs(path: &Path) -> Result<Vec<String>, Error> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read text file {}", path.display()))?;
+    let mut paragraphs = vec![];
+    let mut current: Vec<&str> = vec![];
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(current.join("\n"));
+                current.clear();
+            }
+        } else {
+            current.push(line.trim_end());
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current.join("\n"));
+    }
+    Ok(paragraphs)
 }
 
 fn parse_as_meme_content(input: String, config: &Config) -> Result<MemeContent, Error> {
@@ -90,53 +570,403 @@ fn parse_as_meme_content(input: String, config: &Config) -> Result<MemeContent,
 
 impl Generate {
     fn run(self, config: Config) -> Result<(), Error> {
-        let meme = config.get_meme_template(&self.template)?;
-        eprintln!("Template found");
+        if !self.watch {
+            return self.render(&config);
+        }
 
-        let mut inputs = vec![];
-        for inp in self
-            .inputs
-            .into_iter()
-            .map(|input| parse_as_meme_content(input, &config))
-        {
-            inputs.push(inp?);
+        let dir = match (&self.template_dir, &self.template) {
+            (Some(dir), _) => dir.clone(),
+            // the same lookup the render does, so `--source` picks the copy that's watched
+            (None, Some(template)) => {
+                match config.registry().locate(self.source.as_deref(), template)? {
+                    (memeinator::MemeSource::LocalPath(_), Some(dir)) => dir,
+                    (source, _) => {
+                        return Err(anyhow!(
+                            "{} comes from source {}, which isn't a local directory, \
+                             so there are no edits to watch for",
+                            template,
+                            source.name()
+                        ))
+                    }
+                }
+            }
+            (None, None) => return Err(anyhow!("No template given")),
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Can't watch {}", dir.display()))?;
+        loop {
+            // a broken template shouldn't end the session, it'll likely be fixed soon
+            if let Err(e) = self.render(&config) {
+                log::error!("{:#}", e);
+            }
+            log::info!("Watching {} for changes", dir.display());
+            // rendering reads the template, so skip the access events it causes
+            while rx.recv()??.kind.is_access() {}
+            // editors tend to write in bursts, wait for the last of them
+            while rx.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        }
+    }
+
+    fn render(&self, config: &Config) -> Result<(), Error> {
+        let (mut meme, mut inputs) = match &self.template_dir {
+            Some(dir) => {
+                let inputs = self.template.iter().chain(&self.inputs).cloned().collect();
+                let meme = memeinator::load_template_from_dir(dir)?
+                    .with_linear_blending(config.linear_blending());
+                (meme, inputs)
+            }
+            None => {
+                let template = self
+                    .template
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("No template given"))?;
+                let meme = match &self.source {
+                    Some(source) => config.get_meme_template_from(source, template)?,
+                    None => config.get_meme_template(template)?,
+                };
+                (meme, self.inputs.clone())
+            }
+        };
+        log::info!("Template found");
+        if let Some(path) = &self.text_file {
+            inputs.extend(read_paragraphs(path)?);
         }
-        let mut rendered = meme.render(
-            self.color.unwrap_or(Rgba8(Rgba([0,0,0,255]))).0,
+        let fields = meme.fields().to_vec();
+        let mut json_images = vec![];
+        if let Some(JsonInputs(json_inputs)) = &self.json_inputs {
+            if json_inputs.len() > fields.len() {
+                return Err(anyhow!(
+                    "{} JSON inputs were given, but the template has {} boxes",
+                    json_inputs.len(),
+                    fields.len()
+                ));
+            }
+            inputs = json_inputs.iter().map(|input| input.text.clone()).collect();
+            for (input, field) in json_inputs.iter().zip(meme.fields_mut()) {
+                if let Some(color) = &input.color {
+                    let Rgba8(color) = color.parse().map_err(Error::msg)?;
+                    field.color = Some(color.0.map(|x| x as f32 / u8::MAX as f32));
+                }
+                if let Some(size) = input.max_font_size {
+                    field.max_font_size = Some(size);
+                }
+            }
+            for (index, input) in json_inputs.iter().enumerate() {
+                if let Some(path) = &input.image {
+                    json_images.push((index, path.clone()));
+                }
+            }
+        }
+        for input in &self.named_inputs {
+            let index = fields
+                .iter()
+                .position(|field| field.name.as_deref() == Some(&input.name))
+                .ok_or_else(|| anyhow!("The template has no box named {}", input.name))?;
+            if inputs.len() <= index {
+                inputs.resize(index + 1, String::new());
+            }
+            inputs[index] = input.text.clone();
+        }
+        let template_watermark = meme.watermark().map(|w| w.map(String::from));
+        let text_color = self
+            .color
+            .map(|color| color.0)
+            .or_else(|| meme.text_color())
+            .unwrap_or(Rgba([0, 0, 0, 255]));
+
+        if let Some(filter) = self.filter_type {
+            meme = meme.with_resize_filter(filter);
+        }
+        if let Some(path) = &self.background_image {
+            meme = meme.with_background(&image::open(path)?.into_rgba8());
+        } else if let Some(color) = self.background_color {
+            meme = meme.with_background_color(color.0);
+        }
+        if let Some(max_lines) = self.max_lines {
+            meme = meme.with_max_lines(max_lines);
+        }
+
+        let inputs = {
+            let mut contents = vec![];
+            for inp in inputs
+                .into_iter()
+                .map(|input| parse_as_meme_content(input, config))
+            {
+                contents.push(inp?);
+            }
+            for (index, path) in &json_images {
+                while contents.len() <= *index {
+                    contents.push(MemeContent::Text(String::new()));
+                }
+                contents[*index] = MemeContent::Image(image::open(path)?.into_rgba8());
+            }
+            for image_box in &self.image_box {
+                if image_box.index >= fields.len() {
+                    return Err(anyhow!(
+                        "The template has no box {}, it has {} boxes",
+                        image_box.index,
+                        fields.len()
+                    ));
+                }
+                while contents.len() <= image_box.index {
+                    contents.push(MemeContent::Text(String::new()));
+                }
+                contents[image_box.index] =
+                    MemeContent::Image(image::open(&image_box.path)?.into_rgba8());
+            }
+            contents
+        };
+        let mut ctx = config.render_context()?;
+        if self.measure {
+            let fits = meme.measure(&mut ctx, &inputs, self.max_size.unwrap_or(600.));
+            for (i, fit) in fits.into_iter().enumerate() {
+                match fit {
+                    Some(fit) => eprintln!(
+                        "Box {}: font size {:.1}, {} line(s){}",
+                        i,
+                        fit.font_size,
+                        fit.lines,
+                        if fit.fits { "" } else { ", overflowing" }
+                    ),
+                    None => eprintln!("Box {}: no text", i),
+                }
+            }
+        }
+        if self.strict {
+            check_fits(&meme, &mut ctx, &inputs, self.max_size.unwrap_or(600.))?;
+        }
+        let watermark = pick_watermark(
+            self.watermark.as_ref(),
+            template_watermark.as_ref().map(|w| w.as_deref()),
+            config.watermark(),
+        );
+        if let Some(dir) = &self.debug_dump {
+            std::fs::create_dir_all(dir)?;
+            let masks = meme.text_masks(&mut ctx, &inputs, self.max_size.unwrap_or(600.));
+            for (i, mask) in masks.into_iter().enumerate() {
+                if let Some(mask) = mask {
+                    mask.save(dir.join(format!("box-{}.png", i)))?;
+                }
+            }
+            if let Some(watermark) = watermark {
+                meme.watermark_mask(&mut ctx, watermark, config.watermark_size_fraction())
+                    .save(dir.join("watermark.png"))?;
+            }
+            log::info!("Masks saved to {}", dir.display());
+        }
+        let mut rendered = meme.render_with(
+            &mut ctx,
+            text_color,
             inputs,
             self.max_size.unwrap_or(600.),
-            self.watermark
-                .as_ref()
-                .map(|o| o.as_deref())
-                .unwrap_or_else(|| Some(config.watermark())),
+            watermark,
             config.watermark_size_fraction(),
         );
 
-        if let Some(tt) = self.top_text {
-            rendered = memeinator::add_top_text(rendered, &tt,self.color.unwrap_or(Rgba8(Rgba([0,0,0,255]))).0);
-            
+        if self.print_boxes {
+            memeinator::draw_field_outlines(&mut rendered, &fields, Rgba([255, 0, 0, 255]));
         }
 
-        eprintln!("Meme rendered");
+        if let Some(tt) = &self.top_text {
+            rendered = memeinator::add_top_text_with(
+                &mut ctx,
+                rendered,
+                tt,
+                self.color.unwrap_or(Rgba8(Rgba([0, 0, 0, 255]))).0,
+            );
+        }
 
-        if let Some(out_path) = self.output {
-            if out_path.as_os_str().to_str() == Some("-") {
+        if let Some(filter) = self.filter {
+            rendered = filter.apply(rendered)?;
+        }
+        // kept at one byte per pixel so it's encoded that way, unless it ends up on a color canvas
+        let mut mono = None;
+        if self.mono {
+            let gray = memeinator::mono(&rendered);
+            rendered = DynamicImage::ImageLuma8(gray.clone()).into_rgba8();
+            mono = Some(gray);
+        }
+        if let Some(path) = &self.append_to {
+            let canvas = image::open(path)
+                .with_context(|| format!("Can't open {}", path.display()))?
+                .into_rgba8();
+            rendered = append_onto(canvas, &rendered, self.at.unwrap_or((0, 0)))?;
+            mono = None;
+        }
+
+        log::info!("Meme rendered");
+
+        let default_format = config
+            .default_output_format()
+            .map(parse_format)
+            .transpose()?;
+        let output = self.output.as_ref().or(self.append_to.as_ref());
+        let out_path = match (output, config.default_output()) {
+            (Some(out_path), _) => Some(out_path.clone()),
+            (None, DefaultOutput::Clipboard) => None,
+            (None, DefaultOutput::Stdout) => Some(PathBuf::from("-")),
+            (None, DefaultOutput::Dir(dir)) => {
+                let name = match &self.template_dir {
+                    Some(dir) => dir.file_name().and_then(|name| name.to_str()),
+                    None => self.template.as_deref(),
+                };
+                let format = self.format.or(default_format).unwrap_or(ImageFormat::Png);
+                let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+                let file_name = format!(
+                    "{}-{}.{}",
+                    name.unwrap_or("meme"),
+                    millis,
+                    format.extensions_str()[0]
+                );
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Can't create {}", dir.display()))?;
+                log::info!("Saving to {}", dir.join(&file_name).display());
+                Some(dir.join(file_name))
+            }
+        };
+        if let Some(out_path) = &out_path {
+            let to_stdout = out_path.as_os_str().to_str() == Some("-");
+            let format = if to_stdout {
+                self.format.or(default_format)
+            } else {
+                self.format
+                    .or_else(|| ImageFormat::from_path(out_path).ok())
+                    .or(default_format)
+            }
+            .unwrap_or(ImageFormat::Png);
+
+            if let Some(intensity) = self.shake {
+                if format != ImageFormat::Gif {
+                    return Err(anyhow!(
+                        "--shake makes an animated GIF, but the output format is {:?}",
+                        format
+                    ));
+                }
+                let mut frames = shake_frames(&rendered, intensity, self.shake_frames);
+                if self.global_palette {
+                    if to_stdout {
+                        let mut stdout = std::io::stdout().lock();
+                        write_gif_global_palette(&frames, self.dither, &mut stdout)?;
+                    } else {
+                        let mut file = std::io::BufWriter::new(std::fs::File::create(out_path)?);
+                        write_gif_global_palette(&frames, self.dither, &mut file)?;
+                    }
+                    log::info!("Done!");
+                    return Ok(());
+                }
+                if self.dither == Dither::FloydSteinberg {
+                    frames = frames.iter().map(floyd_steinberg).collect();
+                }
+                if to_stdout {
+                    write_gif(frames, &mut std::io::stdout().lock())?;
+                } else {
+                    let mut file = std::io::BufWriter::new(std::fs::File::create(out_path)?);
+                    write_gif(frames, &mut file)?;
+                }
+                log::info!("Done!");
+                return Ok(());
+            }
+
+            let output = match mono {
+                Some(gray) => DynamicImage::ImageLuma8(gray),
+                None if format == ImageFormat::Gif && self.dither == Dither::FloydSteinberg => {
+                    DynamicImage::ImageRgba8(floyd_steinberg(&rendered))
+                }
+                None => DynamicImage::ImageRgba8(rendered.clone()),
+            };
+
+            if to_stdout {
                 let stdout = std::io::stdout();
                 let mut lock = stdout.lock();
-                let png_encoder = image::png::PngEncoder::new(&mut lock);
-                png_encoder.encode(
-                    rendered.as_bytes(),
-                    rendered.width(),
-                    rendered.height(),
-                    image::ColorType::Rgba8,
-                )?;
+                write_dynamic_image(output, format, &mut lock)?;
             } else {
-                rendered.save(out_path)?;
+                let mut file = std::io::BufWriter::new(std::fs::File::create(out_path)?);
+                write_dynamic_image(output, format, &mut file)?;
+            }
+            if self.also_copy {
+                image_io::image_out(&rendered)?;
             }
         } else {
-            image_io::image_out(rendered)?;
+            image_io::image_out(&rendered)?;
         }
-        eprintln!("Done!");
+        log::info!("Done!");
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "batch", about = "Generate one meme per row of a CSV file")]
+struct Batch {
+    /// The CSV file to read captions from. Each column maps to a text box.
+    #[structopt(long)]
+    csv: PathBuf,
+
+    /// The template to use
+    #[structopt(short, long)]
+    template: String,
+
+    /// The directory the memes are written to, as `row-N.png`
+    #[structopt(short, long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// The maximum font size for the text. Defaults to 600.
+    #[structopt(short, long)]
+    max_size: Option<f32>,
+
+    #[structopt(short, long)]
+    color: Option<Rgba8>,
+}
+
+impl Batch {
+    fn run(self, config: Config) -> Result<(), Error> {
+        let meme = config.get_meme_template(&self.template)?;
+        log::info!("Template found");
+        let text_color = self
+            .color
+            .map(|color| color.0)
+            .or_else(|| meme.text_color())
+            .unwrap_or(Rgba([0, 0, 0, 255]));
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(&self.csv)?;
+        let mut failed = 0;
+        let mut ctx = config.render_context()?;
+        for (i, record) in reader.records().enumerate() {
+            let out_path = self.output_dir.join(format!("row-{}.png", i + 1));
+            let result = record.map_err(Error::from).and_then(|record| {
+                let inputs = record
+                    .iter()
+                    .map(String::from)
+                    .map(MemeContent::Text)
+                    .collect();
+                let rendered = meme.clone().render_with(
+                    &mut ctx,
+                    text_color,
+                    inputs,
+                    self.max_size.unwrap_or(600.),
+                    meme.watermark().unwrap_or_else(|| Some(config.watermark())),
+                    config.watermark_size_fraction(),
+                );
+                rendered.save(&out_path)?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => log::info!("Wrote {}", out_path.display()),
+                Err(e) => {
+                    log::error!("Row {} failed: {:#}", i + 1, e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("{} row(s) failed", failed));
+        }
+        log::info!("Done!");
         Ok(())
     }
 }
@@ -144,43 +974,195 @@ impl Generate {
 #[derive(Debug, StructOpt)]
 #[structopt(name = "make-template", about = "Generate a meme template and save it")]
 struct MakeTemplate {
-    /// The image path for the meme. Can be a URL. If absent, an image will be pulled from the clipboard
+    /// The image path for the meme. Can be a URL. If absent, an image will be pulled from the clipboard,
+    /// or picked with the file picker on termux. Setting this to `-` reads the image from stdin.
     #[structopt(short, long)]
     input: Option<PathBuf>,
 
+    /// Capture the primary screen instead of reading an image
+    #[cfg(feature = "screenshot")]
+    #[structopt(long, conflicts_with = "input")]
+    screenshot: bool,
+
+    /// Only capture this region of the screen, given in `LEFT-TOP-RIGHT-BOTTOM`
+    #[cfg(feature = "screenshot")]
+    #[structopt(long, requires = "screenshot")]
+    region: Option<String>,
+
+    /// The color text in the template is drawn with, unless `generate --color` says otherwise.
+    /// Defaults to black.
+    #[structopt(long)]
+    text_color: Option<Rgba8>,
+
+    /// Trim uniformly colored borders off the image. Coordinates are still given relative
+    /// to the uncropped image.
+    #[structopt(long)]
+    autocrop: bool,
+
+    /// How far a pixel's channels may stray from the border color and still get trimmed
+    #[structopt(long, default_value = "8")]
+    autocrop_tolerance: u8,
+
     /// The template name
     template_name: String,
 
-    /// The coordinates for text, given in `LEFT-TOP-RIGHT-BOTTOM`
+    /// Read the text boxes from a JSON array of boxes as they appear in a template's
+    /// `config.json`, with all their options, instead of from the coordinates
+    #[structopt(long, conflicts_with = "coordinates")]
+    boxes_json: Option<PathBuf>,
+
+    /// Copy the text boxes, with all their options, from an existing template. The image must
+    /// have the same shape; the boxes are scaled if it's a different size.
+    #[structopt(long, conflicts_with_all = &["coordinates", "boxes-json"])]
+    positions_from: Option<String>,
+
+    /// The coordinates for text, given in `LEFT-TOP-RIGHT-BOTTOM`.
+    /// A negative value counts from the right or bottom edge, e.g. `10-10--20-100`.
+    /// Coordinates starting with `-` go after a `--`, like `-- -120-10--20-100`.
     coordinates: Vec<String>,
 }
 
+/// The min and max corners of a rectangle
+type Rect = ((u32, u32), (u32, u32));
+
+/// Parses a `LEFT-TOP-RIGHT-BOTTOM` rectangle. A value written with a second `-`, like
+/// `10-10--200-80`, counts back from the right or bottom edge of an image of `size`.
+fn parse_rect(coord: &str, size: Option<(u32, u32)>) -> Result<Rect, Error> {
+    let e = || anyhow!("Incorrect coordinate literal {}", coord);
+    let mut parts = coord.split('-');
+    let mut values = [0; 4];
+    for (i, value) in values.iter_mut().enumerate() {
+        let part = parts.next().ok_or_else(e)?;
+        *value = if part.is_empty() {
+            let from_edge = parts.next().ok_or_else(e)?.parse::<u32>()?;
+            let (width, height) =
+                size.ok_or_else(|| anyhow!("Coordinates can't count from the edge in {}", coord))?;
+            let edge = if i % 2 == 0 { width } else { height };
+            edge.checked_sub(from_edge)
+                .ok_or_else(|| anyhow!("{} is outside the {}x{} image", coord, width, height))?
+        } else {
+            part.parse()?
+        };
+    }
+    if parts.next().is_some() {
+        return Err(e());
+    }
+    Ok(((values[0], values[1]), (values[2], values[3])))
+}
+
 impl MakeTemplate {
     fn run(self, config: Config) -> Result<(), Error> {
         let img = if let Some(path) = self.input {
-            image::open(path)?.to_rgba8()
+            if path.as_os_str().to_str() == Some("-") {
+                let mut bytes = vec![];
+                std::io::stdin().read_to_end(&mut bytes)?;
+                image::load_from_memory(&bytes)
+                    .context("Couldn't read an image from stdin")?
+                    .into_rgba8()
+            } else {
+                image::open(path)?.into_rgba8()
+            }
         } else {
+            #[cfg(feature = "screenshot")]
+            if self.screenshot {
+                let region = self
+                    .region
+                    .as_deref()
+                    .map(|region| parse_rect(region, None))
+                    .transpose()?;
+                image_io::screenshot(region)?
+            } else {
+                image_io::image_in()?
+            }
+            #[cfg(not(feature = "screenshot"))]
             image_io::image_in()?
         };
-        let mut coords = Vec::with_capacity(self.coordinates.len());
-        for coord in self.coordinates {
-            let mut iterator = coord.split("-").map(str::parse::<u32>);
-            let e = || anyhow!("Incorrect coordinate literal");
-            let text = MemeField {
-                min: (
-                    iterator.next().ok_or_else(e)??,
-                    iterator.next().ok_or_else(e)??,
-                ),
-                max: (
-                    iterator.next().ok_or_else(e)??,
-                    iterator.next().ok_or_else(e)??,
-                ),
+        let size = img.dimensions();
+        let (img, offset) = if self.autocrop {
+            let (min, max) = memeinator::content_bounds(&img, self.autocrop_tolerance);
+            let cropped =
+                image::imageops::crop_imm(&img, min.0, min.1, max.0 - min.0, max.1 - min.1)
+                    .to_image();
+            (cropped, min)
+        } else {
+            (img, (0, 0))
+        };
+        let shift = |(x, y): (u32, u32)| {
+            (
+                x.saturating_sub(offset.0).min(img.width()),
+                y.saturating_sub(offset.1).min(img.height()),
+            )
+        };
+        let coords = if let Some(path) = &self.boxes_json {
+            let json = std::fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read boxes from {}", path.display()))?;
+            let fields: Vec<MemeField> = serde_json::from_str(&json)
+                .with_context(|| format!("Couldn't parse boxes in {}", path.display()))?;
+            fields
+                .into_iter()
+                .map(|field| MemeField {
+                    min: shift(field.min),
+                    max: shift(field.max),
+                    ..field
+                })
+                .collect()
+        } else if let Some(name) = &self.positions_from {
+            let template = config.get_meme_template(name)?;
+            let (width, height) = template.dimensions();
+            let (scale_x, scale_y) = (size.0 as f32 / width as f32, size.1 as f32 / height as f32);
+            if (scale_x / scale_y - 1.).abs() > 0.01 {
+                return Err(anyhow!(
+                    "The {}x{} image doesn't have the shape of {}, which is {}x{}",
+                    size.0,
+                    size.1,
+                    name,
+                    width,
+                    height
+                ));
+            }
+            let scale = |(x, y): (u32, u32)| {
+                (
+                    (x as f32 * scale_x).round() as u32,
+                    (y as f32 * scale_y).round() as u32,
+                )
             };
-            coords.push(text);
-        }
+            let scale_px = |px: i32| (px as f32 * scale_x).round() as i32;
+            template
+                .fields()
+                .iter()
+                .map(|field| MemeField {
+                    min: shift(scale(field.min)),
+                    max: shift(scale(field.max)),
+                    min_font_size: field.min_font_size.map(|px| px * scale_x),
+                    max_font_size: field.max_font_size.map(|px| px * scale_x),
+                    padding: field.padding.map(|(left, top, right, bottom)| {
+                        (
+                            scale_px(left),
+                            scale_px(top),
+                            scale_px(right),
+                            scale_px(bottom),
+                        )
+                    }),
+                    ..field.clone()
+                })
+                .collect()
+        } else {
+            let mut coords = Vec::with_capacity(self.coordinates.len());
+            for coord in self.coordinates {
+                let (min, max) = parse_rect(&coord, Some(size))?;
+                coords.push(MemeField {
+                    min: shift(min),
+                    max: shift(max),
+                    ..Default::default()
+                });
+            }
+            coords
+        };
+        let text_color = self.text_color.map_or([0, 0, 0, 255], |color| color.0 .0);
         let meme_config = MemeConfig {
-            color: Some([0., 0., 0., 1.]),
+            color: Some(text_color.map(|x| x as f32 / u8::MAX as f32)),
             text: coords,
+            ..Default::default()
         };
         config.write_template(
             img.as_raw(),
@@ -188,18 +1170,104 @@ impl MakeTemplate {
             img.height(),
             meme_config,
             &self.template_name,
-        )
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Show where the config, cache and sources live")]
+struct Where {
+    /// Open the cache directory in the file manager
+    #[structopt(long)]
+    open: bool,
+}
+
+impl Where {
+    fn run(self, config: Config) -> Result<(), Error> {
+        let cache_dir = memeinator::cache_dir()?;
+        println!("Config file: {}", Config::path()?.display());
+        println!("Cache directory: {}", cache_dir.display());
+        for source in config.fetch_source_list() {
+            let name = source.name();
+            match source.to_path()? {
+                Some(path) => println!("Source {}: {}", name, path.display()),
+                None => println!("Source {}: built into the binary", name),
+            }
+        }
+        if self.open {
+            open::that(&cache_dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Delete cached clones of git sources so they can be fetched again")]
+struct Clean {
+    /// Only clean the git source with this alias
+    alias: Option<String>,
+
+    /// Don't ask for confirmation
+    #[structopt(short, long)]
+    yes: bool,
+}
+
+impl Clean {
+    fn run(self, config: Config) -> Result<(), Error> {
+        let mut paths = vec![];
+        for source in config.fetch_source_list() {
+            if let memeinator::MemeSource::GitUrl { alias, .. } = source {
+                if self.alias.as_ref().is_none_or(|a| a == alias) {
+                    paths.extend(source.cache_path()?.filter(|path| path.exists()));
+                }
+            }
+        }
+        if paths.is_empty() {
+            log::info!("Nothing to clean");
+            return Ok(());
+        }
+
+        if !self.yes {
+            for path in &paths {
+                eprintln!("{}", path.display());
+            }
+            eprint!("Delete these directories? [y/N] ");
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                return Ok(());
+            }
+        }
+        for path in paths {
+            std::fs::remove_dir_all(&path)?;
+            log::info!("Deleted {}", path.display());
+        }
+        Ok(())
     }
 }
 
 fn main() -> Result<(), Error> {
+    let cli = Cli::from_args();
+    logger::init(cli.verbose);
     let config = Config::load()?;
-    match Opt::from_args() {
+    // a cache that can't be fixed up is no reason to stop, it's only warned about
+    if let Err(e) = config.migrate_cache() {
+        log::warn!("Couldn't check the cache dir: {}", e);
+    }
+    match cli.command {
         Opt::Generate(generate) => generate.run(config),
         Opt::MakeTemplate(make_template) => make_template.run(config),
+        Opt::Batch(batch) => batch.run(config),
         Opt::ListSources => list_sources(config),
-        Opt::ListTemplates => list_templates(config),
-        Opt::UpdateSources => update_sources(config),
+        Opt::ListTemplates(list) => list.run(config),
+        Opt::UpdateSources(update) => update.run(config),
+        Opt::Where(where_) => where_.run(config),
+        Opt::Clean(clean) => clean.run(config),
+        Opt::ValidateTemplate(validate) => validate.run(config),
+        Opt::Search(search) => search.run(config),
+        #[cfg(feature = "server")]
+        Opt::Serve(serve) => serve.run(config),
         Opt::GenerateProtoCompletions(completions) => {
             let shell = match completions {
                 GenerateProtoCompletions::Bash => Shell::Bash,
@@ -208,34 +1276,332 @@ fn main() -> Result<(), Error> {
                 GenerateProtoCompletions::Elvish => Shell::Elvish,
                 GenerateProtoCompletions::PowerShell => Shell::PowerShell,
             };
-            Opt::clap().gen_completions_to("meme-cli", shell, &mut std::io::stdout());
+            Cli::clap().gen_completions_to("meme-cli", shell, &mut std::io::stdout());
             Ok(())
         }
     }
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Check that a template loads and its text boxes make sense")]
+struct ValidateTemplate {
+    /// The template to check
+    name: String,
+}
+
+impl ValidateTemplate {
+    fn run(self, config: Config) -> Result<(), Error> {
+        let meme = match config.get_meme_template(&self.name) {
+            Ok(meme) => meme,
+            Err(e) => {
+                println!("FAIL {}: {:#}", self.name, Error::from(e));
+                return Err(anyhow!("{} failed validation", self.name));
+            }
+        };
+        let (width, height) = meme.dimensions();
+        println!("ok   image decodes, {}x{}", width, height);
+        // loading already rejects boxes that are inverted or out of bounds
+        for (i, field) in meme.fields().iter().enumerate() {
+            println!(
+                "ok   box {}{}: {:?} to {:?}",
+                i,
+                field
+                    .name
+                    .as_ref()
+                    .map_or(String::new(), |name| format!(" ({})", name)),
+                field.min,
+                field.max
+            );
+        }
+        if !meme.config_round_trips() {
+            println!("FAIL config changes when saved and loaded again");
+            return Err(anyhow!("{} failed validation", self.name));
+        }
+        println!("ok   config round-trips");
+        println!("{} passed", self.name);
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Find templates by name and show the matches side by side to pick from")]
+struct Search {
+    /// Letters of the template name, in order. They don't have to be next to each other,
+    /// so `dbt` finds `drake-bad-thing`.
+    query: String,
+
+    /// Where to save the contact sheet, `-` for stdout. Goes to the clipboard otherwise.
+    #[structopt(short, long)]
+    output: Option<PathBuf>,
+
+    /// How many templates go in a row
+    #[structopt(long, default_value = "4")]
+    columns: u32,
+
+    /// The size in pixels each template is shrunk to fit
+    #[structopt(long, default_value = "200", parse(try_from_str = parse_nonzero))]
+    thumbnail_size: u32,
+}
+
+/// Whether the letters of `query` appear in `name` in order, ignoring case
+fn fuzzy_matches(name: &str, query: &str) -> bool {
+    let mut name = name.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|q| name.any(|c| c == q))
+}
+
+impl Search {
+    fn run(self, config: Config) -> Result<(), Error> {
+        let query = self.query.to_lowercase();
+        let mut names: Vec<String> = vec![];
+        for template in config.fetch_template_info_list() {
+            // a name in several sources is the first source's template
+            if fuzzy_matches(&template.name, &query) && !names.contains(&template.name) {
+                names.push(template.name);
+            }
+        }
+        // names containing the query as typed come before looser matches
+        names.sort_by_key(|name| (!name.to_lowercase().contains(&query), name.len()));
+        if names.is_empty() {
+            return Err(anyhow!("No template matches {}", self.query));
+        }
+
+        let to_stdout = self.output.as_ref().and_then(|path| path.to_str()) == Some("-");
+        let mut ctx = config.render_context()?;
+        let mut thumbnails = Vec::with_capacity(names.len());
+        for name in &names {
+            let template = match config.get_meme_template(name) {
+                Ok(template) => template,
+                Err(e) => {
+                    log::warn!("Skipping {}: {:#}", name, Error::from(e));
+                    continue;
+                }
+            };
+            if !to_stdout {
+                println!("{}", name);
+            }
+            let (width, height) = template.dimensions();
+            let scale = self.thumbnail_size as f32 / width.max(height) as f32;
+            thumbnails.push((
+                name,
+                image::imageops::thumbnail(
+                    template.image(),
+                    ((width as f32 * scale).round() as u32).max(1),
+                    ((height as f32 * scale).round() as u32).max(1),
+                ),
+            ));
+        }
+        if thumbnails.is_empty() {
+            return Err(anyhow!(
+                "None of the templates matching {} load",
+                self.query
+            ));
+        }
+
+        // each template gets a cell with its name on top
+        let gap = 10;
+        let label_height = (self.thumbnail_size / 6).max(12);
+        let label = MemeField {
+            min: (0, 0),
+            max: (self.thumbnail_size, label_height),
+            single_line: true,
+            ..Default::default()
+        };
+        let columns = self.columns.clamp(1, thumbnails.len() as u32);
+        let rows = (thumbnails.len() as u32).div_ceil(columns);
+        let cell_width = self.thumbnail_size;
+        let cell_height = label_height + thumbnails.iter().map(|(_, t)| t.height()).max().unwrap();
+        let mut sheet = RgbaImage::from_pixel(
+            columns * (cell_width + gap) + gap,
+            rows * (cell_height + gap) + gap,
+            Rgba([255; 4]),
+        );
+        for (i, (name, thumbnail)) in thumbnails.iter().enumerate() {
+            let (column, row) = (i as u32 % columns, i as u32 / columns);
+            let (x, y) = (
+                gap + column * (cell_width + gap),
+                gap + row * (cell_height + gap),
+            );
+            let mask = ctx.text_mask(&label, FillMode::Fit, name, label_height as f32);
+            for (mask_x, mask_y, coverage) in mask.enumerate_pixels() {
+                let value = u8::MAX - coverage.0[0];
+                sheet.put_pixel(x + mask_x, y + mask_y, Rgba([value, value, value, 255]));
+            }
+            let thumbnail_x = x + (cell_width - thumbnail.width()) / 2;
+            image::imageops::overlay(&mut sheet, thumbnail, thumbnail_x, y + label_height);
+        }
+
+        match &self.output {
+            Some(_) if to_stdout => {
+                write_image(&sheet, ImageFormat::Png, &mut std::io::stdout().lock())?
+            }
+            Some(path) => sheet.save(path)?,
+            None => image_io::image_out(&sheet)?,
+        }
+        Ok(())
+    }
+}
+
 fn list_sources(config: Config) -> Result<(), Error> {
     for source in config.fetch_source_list() {
         match source {
-            memeinator::MemeSource::GitUrl { url, alias } => {
-                println!("Git source {} (URL: {})", alias, url)
+            memeinator::MemeSource::GitUrl { url, alias, sparse } => {
+                let sparse = if *sparse { ", sparse" } else { "" };
+                println!("Git source {} (URL: {}{})", alias, url, sparse)
             }
             memeinator::MemeSource::LocalPath(path) => println!("Local source @ {}", path),
+            memeinator::MemeSource::Embedded => println!("Embedded templates"),
         }
     }
     Ok(())
 }
 
-fn update_sources(config: Config) -> Result<(), Error> {
-    for source in config.fetch_source_list() {
-        source.to_path_and_update()?;
+#[derive(Debug, StructOpt)]
+struct UpdateSources {
+    /// Give up on a git source after this many seconds, overriding `git_timeout_secs`
+    #[structopt(long)]
+    timeout: Option<u64>,
+}
+
+impl UpdateSources {
+    fn run(self, config: Config) -> Result<(), Error> {
+        let timeout = self
+            .timeout
+            .map_or_else(|| config.git_timeout(), Duration::from_secs);
+        let mut failed = 0;
+        for source in config.fetch_source_list() {
+            if let Err(e) = source.to_path_and_update(timeout) {
+                log::error!("{:#}", e);
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow!("{} source(s) failed to update", failed));
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-fn list_templates(config: Config) -> Result<(), Error> {
-    for template in config.fetch_template_list() {
-        println!("{}", template)
+#[derive(Debug, StructOpt)]
+struct ListTemplates {
+    /// Only list animated templates
+    #[structopt(long, conflicts_with = "simple-only")]
+    animated_only: bool,
+
+    /// Only list still image templates
+    #[structopt(long)]
+    simple_only: bool,
+}
+
+impl ListTemplates {
+    fn run(self, config: Config) -> Result<(), Error> {
+        for template in config.fetch_template_info_list() {
+            if (self.animated_only && !template.animated) || (self.simple_only && template.animated)
+            {
+                continue;
+            }
+            println!("{}", template.name)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_rejects_overflow_that_is_otherwise_clipped() {
+        let config = MemeConfig {
+            text: vec![MemeField {
+                min: (0, 0),
+                max: (400, 100),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let meme = MemeTemplate::from_image(RgbaImage::new(400, 100), config).unwrap();
+        let mut ctx = RenderContext::new();
+        let inputs = vec![MemeContent::Text("WORD ".repeat(2000))];
+
+        assert!(check_fits(&meme, &mut ctx, &inputs, 600.).is_err());
+        let rendered = meme.render_with(&mut ctx, Rgba([0, 0, 0, 255]), inputs, 600., None, 30.);
+        assert_eq!(rendered.dimensions(), (400, 100));
+    }
+
+    #[test]
+    fn output_has_no_metadata() {
+        let img = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+
+        let mut png = vec![];
+        write_image(&img, ImageFormat::Png, &mut png).unwrap();
+        let mut chunks = vec![];
+        let mut rest = &png[8..];
+        while rest.len() >= 12 {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            chunks.push(String::from_utf8_lossy(&rest[4..8]).into_owned());
+            rest = &rest[12 + len..];
+        }
+        assert_eq!(chunks, ["IHDR", "IDAT", "IEND"]);
+
+        let mut jpeg = vec![];
+        write_image(&img, ImageFormat::Jpeg, &mut jpeg).unwrap();
+        // the segments before the image data, after the start of image marker
+        let mut markers = vec![];
+        let mut i = 2;
+        while jpeg[i] == 0xff && jpeg[i + 1] != 0xda {
+            markers.push(jpeg[i + 1]);
+            i += 2 + u16::from_be_bytes([jpeg[i + 2], jpeg[i + 3]]) as usize;
+        }
+        assert!(!markers.is_empty());
+        // APP1 holds EXIF
+        assert!(!markers.contains(&0xe1), "{:x?}", markers);
+    }
+
+    #[test]
+    fn watermark_absent_uses_template_then_config() {
+        assert_eq!(pick_watermark(None, None, "config"), Some("config"));
+        assert_eq!(
+            pick_watermark(None, Some(Some("tmpl")), "config"),
+            Some("tmpl")
+        );
+        assert_eq!(pick_watermark(None, Some(None), "config"), None);
+    }
+
+    #[test]
+    fn bare_watermark_uses_config() {
+        assert_eq!(
+            pick_watermark(Some(&None), Some(Some("tmpl")), "config"),
+            Some("config")
+        );
+        assert_eq!(
+            pick_watermark(Some(&None), Some(None), "config"),
+            Some("config")
+        );
+    }
+
+    #[test]
+    fn empty_watermark_disables_it() {
+        let arg = Some(String::new());
+        assert_eq!(
+            pick_watermark(Some(&arg), Some(Some("tmpl")), "config"),
+            None
+        );
+        assert_eq!(pick_watermark(Some(&arg), None, "config"), None);
+    }
+
+    #[test]
+    fn custom_watermark_wins() {
+        let arg = Some("custom".to_owned());
+        assert_eq!(
+            pick_watermark(Some(&arg), Some(Some("tmpl")), "config"),
+            Some("custom")
+        );
+        assert_eq!(
+            pick_watermark(Some(&arg), Some(None), "config"),
+            Some("custom")
+        );
     }
-    Ok(())
 }
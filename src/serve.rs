@@ -0,0 +1,89 @@
+use std::{
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+};
+
+use anyhow::{anyhow, Error};
+use image::{ImageFormat, Rgba};
+use memeinator::{Config, MemeContent, MemeError, RenderContext};
+use structopt::StructOpt;
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::write_image;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Serve memes over HTTP at `GET /meme/<template>?text=...&text=...`")]
+pub struct Serve {
+    /// The address to listen on
+    #[structopt(default_value = "127.0.0.1:8080")]
+    addr: SocketAddr,
+}
+
+impl Serve {
+    pub fn run(self, config: Config) -> Result<(), Error> {
+        let server = Server::http(self.addr).map_err(|e| anyhow!(e))?;
+        log::info!("Listening on http://{}", self.addr);
+        let mut ctx = config.render_context()?;
+        for request in server.incoming_requests() {
+            // one bad meme mustn't take the server down for everyone
+            let handled =
+                panic::catch_unwind(AssertUnwindSafe(|| handle(&request, &config, &mut ctx)))
+                    .unwrap_or_else(|_| {
+                        // the panic may have left the context half updated
+                        ctx = config.render_context().map_err(|e| (500, e.to_string()))?;
+                        Err((500, "Rendering failed".to_owned()))
+                    });
+            let response = match handled {
+                Ok(png) => Response::from_data(png)
+                    .with_header("Content-Type: image/png".parse::<Header>().unwrap()),
+                Err((status, message)) => Response::from_string(message).with_status_code(status),
+            };
+            if let Err(e) = request.respond(response) {
+                log::error!("Failed to respond: {}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn handle(
+    request: &Request,
+    config: &Config,
+    ctx: &mut RenderContext,
+) -> Result<Vec<u8>, (u16, String)> {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let template = path
+        .strip_prefix("/meme/")
+        .filter(|template| !template.is_empty())
+        .ok_or_else(|| (404, "Expected /meme/<template>".to_owned()))?;
+    let template = percent_encoding::percent_decode_str(template)
+        .decode_utf8()
+        .map_err(|e| (400, e.to_string()))?;
+
+    let inputs = form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == "text")
+        .map(|(_, value)| MemeContent::Text(value.into_owned()))
+        .collect();
+
+    let meme = config.get_meme_template(&template).map_err(|e| match e {
+        MemeError::TemplateNotFound(_) => (404, e.to_string()),
+        e => (500, e.to_string()),
+    })?;
+    let color = meme.text_color().unwrap_or(Rgba([0, 0, 0, 255]));
+    let watermark = meme.watermark().map(|w| w.map(String::from));
+    let rendered = meme.render_with(
+        ctx,
+        color,
+        inputs,
+        600.,
+        watermark
+            .as_ref()
+            .map(|w| w.as_deref())
+            .unwrap_or_else(|| Some(config.watermark())),
+        config.watermark_size_fraction(),
+    );
+
+    let mut png = vec![];
+    write_image(&rendered, ImageFormat::Png, &mut png).map_err(|e| (500, e.to_string()))?;
+    Ok(png)
+}